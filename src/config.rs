@@ -1,24 +1,105 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use serde::Deserialize;
 
-use crate::util::parse_order;
+use crate::{
+    preset::Preset,
+    sort::{DeriveGroup, GroupOrder, ItemKind, SortMode, Style, TrailingComma, Unlisted},
+    util::parse_order,
+};
 
 const CONFIG_FILE_NAMES: &[&str] = &[".sort-derives.toml", "sort-derives.toml"];
 
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Config {
     pub order: Option<Vec<String>>,
+    pub preset: Option<Preset>,
     pub preserve: Option<bool>,
+    pub unlisted: Option<Unlisted>,
+    pub sort_mode: Option<SortMode>,
     pub exclude: Option<Vec<String>>,
+    pub include_comments: Option<bool>,
+    pub style: Option<Style>,
+    pub trailing_comma: Option<TrailingComma>,
+    pub reflow: Option<bool>,
+    pub vertical_threshold: Option<usize>,
+    pub merge: Option<bool>,
+    pub split: Option<Vec<Vec<String>>>,
+    pub dedupe: Option<bool>,
+    pub groups: Option<HashMap<String, DeriveGroup>>,
+    pub constraints: Option<Vec<String>>,
+    pub pair_partial_traits: Option<bool>,
+    pub pairs: Option<Vec<(String, String)>>,
+    pub kind: Option<HashMap<ItemKind, KindConfig>>,
+    pub overrides: Option<Vec<ConfigOverride>>,
+    pub aliases: Option<HashMap<String, String>>,
+    pub order_command: Option<Vec<String>>,
+}
+
+/// The settings in `[kind.<kind>]` that override the top-level ones for that kind of item.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KindConfig {
+    pub order: Option<Vec<String>>,
+    pub preserve: Option<bool>,
+    pub unlisted: Option<Unlisted>,
+}
+
+/// The settings in an `[[overrides]]` block, applied to the files matching `files`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigOverride {
+    pub files: Vec<String>,
+    pub config: Config,
 }
 
 impl From<InternalConfig> for Config {
     fn from(internal_config: InternalConfig) -> Self {
         Config {
             order: internal_config.order.map(Into::into),
+            preset: internal_config.preset,
             preserve: internal_config.preserve,
+            unlisted: internal_config.unlisted,
+            sort_mode: internal_config.sort_mode,
             exclude: internal_config.exclude,
+            include_comments: internal_config.include_comments,
+            style: internal_config.style,
+            trailing_comma: internal_config.trailing_comma,
+            reflow: internal_config.reflow,
+            vertical_threshold: internal_config.vertical_threshold,
+            merge: internal_config.merge,
+            split: internal_config.split,
+            dedupe: internal_config.dedupe,
+            groups: internal_config.groups.map(|groups| {
+                groups
+                    .into_iter()
+                    .map(|(name, group)| (name, group.into()))
+                    .collect()
+            }),
+            constraints: internal_config.constraints,
+            pair_partial_traits: internal_config.pair_partial_traits,
+            pairs: internal_config.pairs,
+            kind: internal_config.kind.map(|kind| {
+                kind.into_iter()
+                    .map(|(kind, kind_config)| {
+                        let kind_config = KindConfig {
+                            order: kind_config.order.map(Into::into),
+                            preserve: kind_config.preserve,
+                            unlisted: kind_config.unlisted,
+                        };
+                        (kind, kind_config)
+                    })
+                    .collect()
+            }),
+            overrides: internal_config.overrides.map(|overrides| {
+                overrides
+                    .into_iter()
+                    .map(|o| ConfigOverride {
+                        files: o.files,
+                        config: o.config.into(),
+                    })
+                    .collect()
+            }),
+            aliases: internal_config.aliases,
+            order_command: internal_config.order_command,
         }
     }
 }
@@ -26,8 +107,41 @@ impl From<InternalConfig> for Config {
 #[derive(Debug, Default, Deserialize)]
 struct InternalConfig {
     order: Option<OrderType>,
+    preset: Option<Preset>,
     preserve: Option<bool>,
+    unlisted: Option<Unlisted>,
+    sort_mode: Option<SortMode>,
     exclude: Option<Vec<String>>,
+    include_comments: Option<bool>,
+    style: Option<Style>,
+    trailing_comma: Option<TrailingComma>,
+    reflow: Option<bool>,
+    vertical_threshold: Option<usize>,
+    merge: Option<bool>,
+    split: Option<Vec<Vec<String>>>,
+    dedupe: Option<bool>,
+    groups: Option<HashMap<String, GroupType>>,
+    constraints: Option<Vec<String>>,
+    pair_partial_traits: Option<bool>,
+    pairs: Option<Vec<(String, String)>>,
+    kind: Option<HashMap<ItemKind, InternalKindConfig>>,
+    overrides: Option<Vec<InternalConfigOverride>>,
+    aliases: Option<HashMap<String, String>>,
+    order_command: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InternalConfigOverride {
+    files: Vec<String>,
+    #[serde(flatten)]
+    config: InternalConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct InternalKindConfig {
+    order: Option<OrderType>,
+    preserve: Option<bool>,
+    unlisted: Option<Unlisted>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,7 +160,91 @@ impl From<OrderType> for Vec<String> {
     }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum GroupType {
+    Array(Vec<String>),
+    Table {
+        derives: Vec<String>,
+        order: Option<GroupOrder>,
+    },
+}
+
+impl From<GroupType> for DeriveGroup {
+    fn from(group_type: GroupType) -> Self {
+        match group_type {
+            GroupType::Array(derives) => DeriveGroup {
+                derives,
+                ..Default::default()
+            },
+            GroupType::Table { derives, order } => DeriveGroup {
+                derives,
+                order: order.unwrap_or_default(),
+            },
+        }
+    }
+}
+
 impl Config {
+    /// Returns the directory where the config file is looked up.
+    pub fn dir(config_file_path: &Option<String>) -> PathBuf {
+        config_file_path
+            .as_ref()
+            .and_then(|p| PathBuf::from(p).parent().map(PathBuf::from))
+            .unwrap_or_else(|| std::env::current_dir().unwrap())
+    }
+
+    /// Returns the config with the settings of `other` taking precedence.
+    /// `groups`, `kind` and `aliases` are merged by name, and `exclude` patterns are added.
+    pub fn merge(&self, other: &Config) -> Config {
+        fn merge_maps<K: Clone + Eq + std::hash::Hash, V: Clone>(
+            a: &Option<HashMap<K, V>>,
+            b: &Option<HashMap<K, V>>,
+        ) -> Option<HashMap<K, V>> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.clone().into_iter().chain(b.clone()).collect()),
+                _ => b.clone().or_else(|| a.clone()),
+            }
+        }
+
+        Config {
+            order: other.order.clone().or_else(|| self.order.clone()),
+            preset: other.preset.or(self.preset),
+            preserve: other.preserve.or(self.preserve),
+            // `preserve` is resolved within each config first, so that it is not overridden by `unlisted` of the other
+            unlisted: resolve_unlisted(other.unlisted, other.preserve)
+                .or(resolve_unlisted(self.unlisted, self.preserve)),
+            sort_mode: other.sort_mode.or(self.sort_mode),
+            exclude: match (&self.exclude, &other.exclude) {
+                (Some(a), Some(b)) => Some([a.clone(), b.clone()].concat()),
+                (a, b) => b.clone().or_else(|| a.clone()),
+            },
+            include_comments: other.include_comments.or(self.include_comments),
+            style: other.style.or(self.style),
+            trailing_comma: other.trailing_comma.or(self.trailing_comma),
+            reflow: other.reflow.or(self.reflow),
+            vertical_threshold: other.vertical_threshold.or(self.vertical_threshold),
+            merge: other.merge.or(self.merge),
+            split: other.split.clone().or_else(|| self.split.clone()),
+            dedupe: other.dedupe.or(self.dedupe),
+            groups: merge_maps(&self.groups, &other.groups),
+            constraints: other
+                .constraints
+                .clone()
+                .or_else(|| self.constraints.clone()),
+            pair_partial_traits: other.pair_partial_traits.or(self.pair_partial_traits),
+            pairs: other.pairs.clone().or_else(|| self.pairs.clone()),
+            kind: merge_maps(&self.kind, &other.kind),
+            // overrides cannot be nested
+            overrides: self.overrides.clone(),
+            aliases: merge_maps(&self.aliases, &other.aliases),
+            order_command: other
+                .order_command
+                .clone()
+                .or_else(|| self.order_command.clone()),
+        }
+    }
+
     pub fn load(config_file_path: &Option<String>) -> Config {
         let paths = config_file_path
             .as_ref()
@@ -72,6 +270,17 @@ fn load_from(config_file_paths: Vec<PathBuf>) -> Config {
     }
 }
 
+/// `preserve` is an alias for `unlisted = "preserve"`, and `unlisted` takes precedence if both are specified.
+pub fn resolve_unlisted(unlisted: Option<Unlisted>, preserve: Option<bool>) -> Option<Unlisted> {
+    unlisted.or(preserve.map(|preserve| {
+        if preserve {
+            Unlisted::Preserve
+        } else {
+            Unlisted::Alphabetical
+        }
+    }))
+}
+
 fn first_exist_path(paths: Vec<PathBuf>) -> Option<PathBuf> {
     paths.into_iter().find(|p| p.exists())
 }
@@ -118,11 +327,394 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_config_deserialize_include_comments() {
+        let toml = r#"
+            include_comments = true
+        "#;
+        let expected = Config {
+            include_comments: Some(true),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_style() {
+        let toml = r#"
+            style = "preserve"
+            trailing_comma = "always"
+        "#;
+        let expected = Config {
+            style: Some(Style::Preserve),
+            trailing_comma: Some(TrailingComma::Always),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_layout() {
+        let toml = r#"
+            reflow = true
+            vertical_threshold = 5
+            merge = true
+            split = [["Clone", "Debug"], ["serde::Serialize"]]
+        "#;
+        let expected = Config {
+            reflow: Some(true),
+            vertical_threshold: Some(5),
+            merge: Some(true),
+            split: Some(vec![
+                vec!["Clone".into(), "Debug".into()],
+                vec!["serde::Serialize".into()],
+            ]),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_dedupe() {
+        let toml = r#"
+            dedupe = true
+        "#;
+        let expected = Config {
+            dedupe: Some(true),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_unlisted() {
+        let toml = r#"
+            unlisted = "by-crate"
+
+            [kind.enum]
+            unlisted = "by-frequency"
+        "#;
+        let expected = Config {
+            unlisted: Some(Unlisted::ByCrate),
+            kind: Some(HashMap::from([(
+                ItemKind::Enum,
+                KindConfig {
+                    unlisted: Some(Unlisted::ByFrequency),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_order_command() {
+        let toml = r#"
+            order_command = ["./tools/derive-order", "--strict"]
+        "#;
+        let expected = Config {
+            order_command: Some(vec!["./tools/derive-order".into(), "--strict".into()]),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_aliases() {
+        let toml = r#"
+            [aliases]
+            "serde_derive::Serialize" = "serde::Serialize"
+            "my_macros::Builder" = "derive_builder::Builder"
+        "#;
+        let expected = Config {
+            aliases: Some(HashMap::from([
+                ("serde_derive::Serialize".into(), "serde::Serialize".into()),
+                (
+                    "my_macros::Builder".into(),
+                    "derive_builder::Builder".into(),
+                ),
+            ])),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_sort_mode() {
+        let toml = r#"
+            sort_mode = "case-insensitive"
+        "#;
+        let expected = Config {
+            sort_mode: Some(SortMode::CaseInsensitive),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_groups() {
+        let toml = r#"
+            order = ["@std", "...", "@serde"]
+
+            [groups]
+            std = ["Debug", "Clone"]
+            serde = { derives = ["serde::*"], order = "alphabetical" }
+        "#;
+        let expected = Config {
+            order: Some(vec!["@std".into(), "...".into(), "@serde".into()]),
+            groups: Some(HashMap::from([
+                (
+                    "std".into(),
+                    DeriveGroup {
+                        derives: vec!["Debug".into(), "Clone".into()],
+                        order: GroupOrder::Listed,
+                    },
+                ),
+                (
+                    "serde".into(),
+                    DeriveGroup {
+                        derives: vec!["serde::*".into()],
+                        order: GroupOrder::Alphabetical,
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_preset() {
+        let toml = r#"
+            preset = "api-guidelines"
+            order = ["...", "Serialize"]
+        "#;
+        let expected = Config {
+            order: Some(vec!["...".into(), "Serialize".into()]),
+            preset: Some(Preset::ApiGuidelines),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_constraints() {
+        let toml = r#"
+            constraints = ["PartialEq < Eq", "Debug < thiserror::Error"]
+        "#;
+        let expected = Config {
+            constraints: Some(vec![
+                "PartialEq < Eq".into(),
+                "Debug < thiserror::Error".into(),
+            ]),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_pairs() {
+        let toml = r#"
+            pair_partial_traits = true
+            pairs = [["serde::Serialize", "serde::Deserialize"]]
+        "#;
+        let expected = Config {
+            pair_partial_traits: Some(true),
+            pairs: Some(vec![(
+                "serde::Serialize".into(),
+                "serde::Deserialize".into(),
+            )]),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_kind() {
+        let toml = r#"
+            order = ["Debug", "Clone"]
+
+            [kind.enum]
+            order = "Copy, Clone, Debug"
+
+            [kind.struct]
+            preserve = true
+        "#;
+        let expected = Config {
+            order: Some(vec!["Debug".into(), "Clone".into()]),
+            kind: Some(HashMap::from([
+                (
+                    ItemKind::Enum,
+                    KindConfig {
+                        order: Some(vec!["Copy".into(), "Clone".into(), "Debug".into()]),
+                        preserve: None,
+                        unlisted: None,
+                    },
+                ),
+                (
+                    ItemKind::Struct,
+                    KindConfig {
+                        order: None,
+                        preserve: Some(true),
+                        unlisted: None,
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_deserialize_overrides() {
+        let toml = r#"
+            order = ["Debug", "Clone"]
+            exclude = ["generated.rs"]
+
+            [[overrides]]
+            files = ["crates/proto/**", "tests/**"]
+            preserve = true
+            exclude = ["crates/proto/src/lib.rs"]
+
+            [overrides.kind.enum]
+            order = "Copy, Clone"
+        "#;
+        let expected = Config {
+            order: Some(vec!["Debug".into(), "Clone".into()]),
+            exclude: Some(vec!["generated.rs".into()]),
+            overrides: Some(vec![ConfigOverride {
+                files: vec!["crates/proto/**".into(), "tests/**".into()],
+                config: Config {
+                    preserve: Some(true),
+                    exclude: Some(vec!["crates/proto/src/lib.rs".into()]),
+                    kind: Some(HashMap::from([(
+                        ItemKind::Enum,
+                        KindConfig {
+                            order: Some(vec!["Copy".into(), "Clone".into()]),
+                            preserve: None,
+                            unlisted: None,
+                        },
+                    )])),
+                    ..Default::default()
+                },
+            }]),
+            ..Default::default()
+        };
+
+        let actual = deserialize_config(toml);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let base = Config {
+            order: Some(vec!["Debug".into()]),
+            preserve: Some(false),
+            exclude: Some(vec!["a.rs".into()]),
+            groups: Some(HashMap::from([
+                ("a".into(), DeriveGroup::default()),
+                ("b".into(), DeriveGroup::default()),
+            ])),
+            ..Default::default()
+        };
+        let other = Config {
+            preserve: Some(true),
+            exclude: Some(vec!["b.rs".into()]),
+            groups: Some(HashMap::from([(
+                "b".into(),
+                DeriveGroup {
+                    derives: vec!["Clone".into()],
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        let expected = Config {
+            order: Some(vec!["Debug".into()]),
+            preserve: Some(true),
+            unlisted: Some(Unlisted::Preserve),
+            exclude: Some(vec!["a.rs".into(), "b.rs".into()]),
+            groups: Some(HashMap::from([
+                ("a".into(), DeriveGroup::default()),
+                (
+                    "b".into(),
+                    DeriveGroup {
+                        derives: vec!["Clone".into()],
+                        ..Default::default()
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+
+        let actual = base.merge(&other);
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_merge_preserve_over_unlisted() {
+        let base = Config {
+            unlisted: Some(Unlisted::ByCrate),
+            ..Default::default()
+        };
+        let other = Config {
+            preserve: Some(true),
+            ..Default::default()
+        };
+
+        let actual = base.merge(&other);
+
+        assert_eq!(actual.unlisted, Some(Unlisted::Preserve));
+    }
+
     fn config(order: &[&str], preserve: bool, exclude: &[&str]) -> Config {
         Config {
             order: Some(order.iter().map(|s| s.to_string()).collect()),
             preserve: Some(preserve),
             exclude: Some(exclude.iter().map(|s| s.to_string()).collect()),
+            ..Default::default()
         }
     }
 
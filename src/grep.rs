@@ -6,9 +6,14 @@ use std::{
 
 use grep_regex::RegexMatcherBuilder;
 use grep_searcher::{Searcher, SearcherBuilder, Sink, SinkMatch};
-use ignore::{WalkBuilder, WalkParallel, overrides::OverrideBuilder, types::TypesBuilder};
+use ignore::{
+    WalkBuilder, WalkParallel,
+    overrides::{Override, OverrideBuilder},
+    types::TypesBuilder,
+};
 
-const PATTERN: &str = r"#\[(?:derive\([^\)]+\)|cfg_attr\(.+,\s*derive\([^\)]+\)\))";
+// only the line where an attribute starts is matched, as derive lists may span multiple lines
+const PATTERN: &str = r"#\[(?:derive|cfg_attr)\(";
 
 pub type Matches = Vec<(PathBuf, HashSet<usize>)>;
 
@@ -44,6 +49,25 @@ fn grep_all_files<P: AsRef<Path>>(root: P, exclude: Vec<String>) -> Result<Match
     exec_grep(walker)
 }
 
+/// Matches file paths against patterns in the .gitignore format, relative to the current directory.
+#[derive(Debug)]
+pub struct PathMatcher(Override);
+
+impl PathMatcher {
+    pub fn new(globs: &[String]) -> Result<PathMatcher, String> {
+        let mut override_builder = OverrideBuilder::new(".");
+        for glob in globs {
+            override_builder.add(glob).map_err(|e| e.to_string())?;
+        }
+        let matcher = override_builder.build().map_err(|e| e.to_string())?;
+        Ok(PathMatcher(matcher))
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.0.matched(path, false).is_whitelist()
+    }
+}
+
 fn grep_single_file<P: AsRef<Path>>(path: P) -> Result<Matches, String> {
     let path = path.as_ref();
 
@@ -158,6 +182,18 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_path_matcher() {
+        let globs = vec!["crates/proto/**".to_string(), "generated.rs".to_string()];
+        let matcher = PathMatcher::new(&globs).unwrap();
+
+        assert!(matcher.is_match(Path::new("./crates/proto/src/lib.rs")));
+        assert!(matcher.is_match(Path::new("crates/proto/src/lib.rs")));
+        assert!(matcher.is_match(Path::new("./src/generated.rs")));
+        assert!(!matcher.is_match(Path::new("./crates/domain/src/lib.rs")));
+        assert!(!matcher.is_match(Path::new("./src/crates/proto/lib.rs")));
+    }
+
     #[test]
     fn test_grep_all_files_with_exclude() {
         let files = &[
@@ -297,8 +333,20 @@ mod tests {
         
         #[cfg_attr(all(feature = "serde", not(test)), derive(Deserialize, Serialize))]
         struct D;
+
+        #[derive(
+            Debug,
+            Clone,
+        )]
+        struct E;
+
+        #[cfg_attr(
+            feature = "serde",
+            derive(Deserialize, Serialize)
+        )]
+        struct F;
         "#;
-        let derive_lines = HashSet::from([2, 5, 8, 11]);
+        let derive_lines = HashSet::from([2, 5, 8, 11, 14, 20]);
         (source, derive_lines)
     }
 
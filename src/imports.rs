@@ -0,0 +1,299 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use regex::Regex;
+
+use crate::lexer::CodeMap;
+
+static USE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\buse\b").unwrap());
+
+/// Derives in the std prelude, which can be used without importing them.
+const PRELUDE_DERIVES: &[(&str, &str)] = &[
+    ("Clone", "std::clone::Clone"),
+    ("Copy", "std::marker::Copy"),
+    ("Debug", "std::fmt::Debug"),
+    ("Default", "std::default::Default"),
+    ("Eq", "std::cmp::Eq"),
+    ("Hash", "std::hash::Hash"),
+    ("Ord", "std::cmp::Ord"),
+    ("PartialEq", "std::cmp::PartialEq"),
+    ("PartialOrd", "std::cmp::PartialOrd"),
+];
+
+/// What a derive path written in a file refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The full path, e.g. `serde::Serialize` for `Serialize` imported by `use serde::Serialize;`
+    Path(String),
+    /// A bare name that is neither imported nor in the prelude (e.g. brought into scope by a glob import),
+    /// or a path starting with a name imported from more than one path
+    Unresolved,
+}
+
+/// The names brought into scope by the `use` declarations of a file, and the paths they refer to.
+#[derive(Debug, Default)]
+pub struct Imports {
+    /// `None` if the name is imported from more than one path
+    paths: HashMap<String, Option<String>>,
+}
+
+impl Imports {
+    /// Collects the `use` declarations anywhere in the file, regardless of the module they are in.
+    /// Glob imports are ignored, as the names they bring into scope are not known.
+    pub fn new(src: &str, code_map: &CodeMap) -> Imports {
+        let mut paths: HashMap<String, Option<String>> = HashMap::new();
+        for m in USE_RE.find_iter(src) {
+            if !code_map.is_code(m.start()) {
+                continue;
+            }
+            let Some(end) = src[m.end()..]
+                .match_indices(';')
+                .map(|(i, _)| m.end() + i)
+                .find(|i| code_map.is_code(*i))
+            else {
+                break;
+            };
+            let code: String = src[m.end()..end]
+                .char_indices()
+                .map(|(i, c)| {
+                    if code_map.is_code(m.end() + i) {
+                        c
+                    } else {
+                        ' '
+                    }
+                })
+                .collect();
+
+            let tokens = tokenize(&code);
+            let mut imports = Vec::new();
+            parse_use_tree(&tokens, &mut 0, Vec::new(), &mut imports);
+            for (name, path) in imports {
+                paths
+                    .entry(name)
+                    .and_modify(|p| {
+                        if p.as_ref() != Some(&path) {
+                            *p = None;
+                        }
+                    })
+                    .or_insert(Some(path));
+            }
+        }
+        Imports { paths }
+    }
+
+    /// Resolves the first segment of `path` through the imports, and a bare name not imported through the
+    /// std prelude. A path whose first segment is not imported refers to a crate, and is returned as it is.
+    pub fn resolve(&self, path: &str) -> Resolution {
+        let (first, rest) = match path.split_once("::") {
+            Some((first, rest)) => (first, Some(rest)),
+            None => (path, None),
+        };
+        match (self.paths.get(first), rest) {
+            (Some(Some(import)), Some(rest)) => Resolution::Path(format!("{import}::{rest}")),
+            (Some(Some(import)), None) => Resolution::Path(import.clone()),
+            // ambiguous
+            (Some(None), _) => Resolution::Unresolved,
+            (None, Some(_)) => Resolution::Path(path.to_string()),
+            (None, None) => PRELUDE_DERIVES
+                .iter()
+                .find(|(name, _)| *name == path)
+                .map_or(Resolution::Unresolved, |(_, std_path)| {
+                    Resolution::Path(std_path.to_string())
+                }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    PathSep,
+    Star,
+    OpenBrace,
+    CloseBrace,
+    Comma,
+}
+
+fn tokenize(code: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = code.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let token = match c {
+            ':' if chars.next_if(|(_, c)| *c == ':').is_some() => Token::PathSep,
+            '*' => Token::Star,
+            '{' => Token::OpenBrace,
+            '}' => Token::CloseBrace,
+            ',' => Token::Comma,
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                    end = j + c.len_utf8();
+                }
+                Token::Ident(code[i..end].to_string())
+            }
+            _ => continue,
+        };
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Parses a use tree starting at `pos`, and pushes the names it imports with their paths to `imports`.
+fn parse_use_tree(
+    tokens: &[Token],
+    pos: &mut usize,
+    mut prefix: Vec<String>,
+    imports: &mut Vec<(String, String)>,
+) {
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+        match token {
+            // a leading `::`
+            Token::PathSep => {}
+            Token::Ident(ident) if tokens.get(*pos) == Some(&Token::PathSep) => {
+                *pos += 1;
+                prefix.push(ident.clone());
+            }
+            Token::Ident(ident) => {
+                if ident == "self" {
+                    // `self` in a group imports the prefix itself
+                    if prefix.is_empty() {
+                        return;
+                    }
+                } else {
+                    prefix.push(ident.clone());
+                }
+                let mut name = prefix.last().unwrap().clone();
+                if tokens.get(*pos) == Some(&Token::Ident("as".into())) {
+                    match tokens.get(*pos + 1) {
+                        Some(Token::Ident(alias)) => name = alias.clone(),
+                        _ => return,
+                    }
+                    *pos += 2;
+                }
+                if name != "_" {
+                    imports.push((name, prefix.join("::")));
+                }
+                return;
+            }
+            Token::OpenBrace => {
+                while let Some(token) = tokens.get(*pos) {
+                    match token {
+                        Token::CloseBrace => {
+                            *pos += 1;
+                            return;
+                        }
+                        Token::Comma => *pos += 1,
+                        _ => {
+                            let start = *pos;
+                            parse_use_tree(tokens, pos, prefix.clone(), imports);
+                            if *pos == start {
+                                return;
+                            }
+                        }
+                    }
+                }
+                return;
+            }
+            Token::Star | Token::CloseBrace | Token::Comma => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imports(src: &str) -> Imports {
+        Imports::new(src, &CodeMap::new(src))
+    }
+
+    #[test]
+    fn test_resolve() {
+        let imports = imports(
+            r#"
+use serde::Serialize;
+pub(crate) use ::rkyv::{Archive, Deserialize as RkyvDeserialize, self};
+use derive_more::{
+    // Display is not imported from here
+    From, ops::{Add, Mul as _},
+};
+use strum::*;
+// use foo::Bar;
+const S: &str = "use baz::Qux;";
+"#,
+        );
+
+        assert_eq!(
+            imports.resolve("Serialize"),
+            Resolution::Path("serde::Serialize".into())
+        );
+        assert_eq!(
+            imports.resolve("Archive"),
+            Resolution::Path("rkyv::Archive".into())
+        );
+        assert_eq!(
+            imports.resolve("RkyvDeserialize"),
+            Resolution::Path("rkyv::Deserialize".into())
+        );
+        assert_eq!(
+            imports.resolve("rkyv::Serialize"),
+            Resolution::Path("rkyv::Serialize".into())
+        );
+        assert_eq!(
+            imports.resolve("From"),
+            Resolution::Path("derive_more::From".into())
+        );
+        assert_eq!(
+            imports.resolve("Add"),
+            Resolution::Path("derive_more::ops::Add".into())
+        );
+        assert_eq!(imports.resolve("Mul"), Resolution::Unresolved);
+        assert_eq!(imports.resolve("Display"), Resolution::Unresolved);
+        assert_eq!(imports.resolve("Bar"), Resolution::Unresolved);
+        assert_eq!(imports.resolve("Qux"), Resolution::Unresolved);
+        assert_eq!(
+            imports.resolve("Debug"),
+            Resolution::Path("std::fmt::Debug".into())
+        );
+        assert_eq!(
+            imports.resolve("serde::Deserialize"),
+            Resolution::Path("serde::Deserialize".into())
+        );
+    }
+
+    #[test]
+    fn test_resolve_ambiguous() {
+        let imports = imports(
+            r#"
+mod a {
+    use serde::Serialize;
+}
+mod b {
+    use rkyv::Serialize;
+}
+mod c {
+    use serde::Deserialize;
+}
+mod d {
+    use serde::Deserialize;
+}
+"#,
+        );
+
+        assert_eq!(imports.resolve("Serialize"), Resolution::Unresolved);
+        assert_eq!(
+            imports.resolve("Deserialize"),
+            Resolution::Path("serde::Deserialize".into())
+        );
+    }
+
+    #[test]
+    fn test_resolve_shadowing_prelude() {
+        let imports = imports("use my_macros::Debug;\n");
+
+        assert_eq!(
+            imports.resolve("Debug"),
+            Resolution::Path("my_macros::Debug".into())
+        );
+    }
+}
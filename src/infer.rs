@@ -0,0 +1,78 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Returns the order of the derives most consistent with how they are written.
+///
+/// Each derive list votes for every pair of derives in it, and the derive preceding the remaining ones
+/// by the most votes is taken next. Derives that are never written with others are not included.
+pub fn infer_order(derive_lists: &[Vec<String>]) -> Vec<String> {
+    let mut votes: HashMap<(&str, &str), i64> = HashMap::new();
+    let mut names: BTreeSet<&str> = BTreeSet::new();
+    for derive_list in derive_lists {
+        for (i, a) in derive_list.iter().enumerate() {
+            for b in &derive_list[i + 1..] {
+                if a != b {
+                    *votes.entry((a, b)).or_default() += 1;
+                    names.insert(a);
+                    names.insert(b);
+                }
+            }
+        }
+    }
+    let vote = |a: &str, b: &str| votes.get(&(a, b)).copied().unwrap_or(0);
+
+    let mut remaining: Vec<&str> = names.into_iter().collect();
+    let mut order = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let score = |a: &str| -> i64 { remaining.iter().map(|b| vote(a, b) - vote(b, a)).sum() };
+        // ties are broken alphabetically, as `remaining` is sorted
+        let (i, _) = remaining
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, name)| -score(name))
+            .unwrap();
+        order.push(remaining.remove(i).to_string());
+    }
+    order
+}
+
+/// Formats the order to be pasted into the config file.
+pub fn format_order(order: &[String]) -> String {
+    let entries: String = order
+        .iter()
+        .map(|name| format!("  \"{name}\",\n"))
+        .collect();
+    format!("order = [\n{entries}]\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infer_order() {
+        let derive_lists: Vec<Vec<String>> = [
+            vec!["Debug", "Clone", "PartialEq"],
+            vec!["Debug", "Clone"],
+            vec!["Clone", "Debug", "Eq"],
+            vec!["Debug", "PartialEq", "Eq"],
+            vec!["Serialize"],
+            vec!["PartialEq", "Eq", "Hash"],
+        ]
+        .into_iter()
+        .map(|l| l.into_iter().map(Into::into).collect())
+        .collect();
+
+        let actual = infer_order(&derive_lists);
+
+        assert_eq!(actual, vec!["Debug", "Clone", "PartialEq", "Eq", "Hash"]);
+    }
+
+    #[test]
+    fn test_format_order() {
+        let order = vec!["Debug".to_string(), "Clone".to_string()];
+        assert_eq!(
+            format_order(&order),
+            "order = [\n  \"Debug\",\n  \"Clone\",\n]\n"
+        );
+    }
+}
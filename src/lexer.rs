@@ -0,0 +1,212 @@
+use std::ops::Range;
+
+/// Records which parts of a Rust source are not code, i.e. comments and string literals.
+#[derive(Debug, Default)]
+pub struct CodeMap {
+    non_code: Vec<Range<usize>>,
+}
+
+impl CodeMap {
+    pub fn new(src: &str) -> CodeMap {
+        CodeMap {
+            non_code: scan_non_code(src),
+        }
+    }
+
+    /// Returns whether the byte at `offset` is outside of comments and string literals.
+    pub fn is_code(&self, offset: usize) -> bool {
+        let i = self.non_code.partition_point(|r| r.end <= offset);
+        self.non_code.get(i).is_none_or(|r| offset < r.start)
+    }
+}
+
+fn scan_non_code(src: &str) -> Vec<Range<usize>> {
+    let bytes = src.as_bytes();
+    let mut ranges = Vec::new();
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let end = match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => line_comment_end(bytes, i),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => block_comment_end(bytes, i),
+            b'"' => string_end(bytes, i),
+            b'\'' => match char_literal_end(src, i) {
+                Some(end) => end,
+                None => {
+                    // lifetime or label
+                    i += 1;
+                    continue;
+                }
+            },
+            b'r' if is_ident_start(bytes, i) => match raw_string_end(bytes, i) {
+                Some(end) => end,
+                None => {
+                    i = ident_end(bytes, i);
+                    continue;
+                }
+            },
+            b'b' | b'c' if is_ident_start(bytes, i) && bytes.get(i + 1) == Some(&b'r') => {
+                match raw_string_end(bytes, i + 1) {
+                    Some(end) => end,
+                    None => {
+                        i = ident_end(bytes, i);
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        ranges.push(start..end);
+        i = end;
+    }
+
+    ranges
+}
+
+fn line_comment_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(bytes.len(), |p| start + p)
+}
+
+fn block_comment_end(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut i = start;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn char_literal_end(src: &str, start: usize) -> Option<usize> {
+    let bytes = src.as_bytes();
+    match bytes.get(start + 1)? {
+        b'\\' => {
+            // skip the escaped character itself, which may be a quote
+            let mut i = start + 3;
+            while i < bytes.len() && bytes[i] != b'\'' && bytes[i] != b'\n' {
+                i += 1;
+            }
+            (bytes.get(i) == Some(&b'\'')).then_some(i + 1)
+        }
+        _ => {
+            let c = src[start + 1..].chars().next()?;
+            let close = start + 1 + c.len_utf8();
+            (bytes.get(close) == Some(&b'\'')).then_some(close + 1)
+        }
+    }
+}
+
+/// `start` points to the `r` of a raw string prefix (`r"`, `r#"`, ...).
+fn raw_string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let hashes = bytes[start + 1..]
+        .iter()
+        .take_while(|b| **b == b'#')
+        .count();
+    let quote = start + 1 + hashes;
+    if bytes.get(quote) != Some(&b'"') {
+        // raw identifier (`r#type`) or a plain identifier
+        return None;
+    }
+
+    let mut i = quote + 1;
+    while i < bytes.len() {
+        if bytes[i] == b'"'
+            && bytes[i + 1..].len() >= hashes
+            && bytes[i + 1..i + 1 + hashes].iter().all(|b| *b == b'#')
+        {
+            return Some(i + 1 + hashes);
+        }
+        i += 1;
+    }
+    Some(bytes.len())
+}
+
+fn is_ident_start(bytes: &[u8], i: usize) -> bool {
+    i == 0 || !is_ident_byte(bytes[i - 1])
+}
+
+fn ident_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|b| !is_ident_byte(*b))
+        .map_or(bytes.len(), |p| start + p)
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || !b.is_ascii()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_non_code() {
+        let src = r##"a // b
+c /* d /* e */ f */ g
+"h \" i" 'j' '\'' 'k: loop {}
+b"l" r"m" r#"n " o"# br#"p"# r#type
+q"##;
+        let actual: Vec<&str> = scan_non_code(src).into_iter().map(|r| &src[r]).collect();
+        let expected = vec![
+            "// b",
+            "/* d /* e */ f */",
+            r#""h \" i""#,
+            "'j'",
+            r"'\''",
+            r#""l""#,
+            r#"r"m""#,
+            r##"r#"n " o"#"##,
+            r##"br#"p"#"##,
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_scan_non_code_unterminated() {
+        let src = "a /* b";
+        let actual = scan_non_code(src);
+        assert_eq!(actual, vec![2..6]);
+    }
+
+    #[test]
+    fn test_is_code() {
+        let src = "a // b\nc \"d\" e";
+        let code_map = CodeMap::new(src);
+        let actual: String = src
+            .char_indices()
+            .filter(|(i, _)| code_map.is_code(*i))
+            .map(|(_, c)| c)
+            .collect();
+        assert_eq!(actual, "a \nc  e");
+    }
+}
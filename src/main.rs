@@ -1,19 +1,32 @@
 mod config;
 mod ext;
 mod grep;
+mod imports;
+mod infer;
+mod lexer;
+mod order_command;
+mod preset;
 mod process;
+mod rustfmt;
 mod sort;
 mod util;
 
-use std::{io::Read, path::Path};
+use std::{collections::HashMap, io::Read, path::Path, rc::Rc};
 
-use clap::{Args, Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 use crate::{
-    config::Config,
-    grep::grep,
-    process::process,
-    sort::{sort, sort_stdin},
+    config::{Config, resolve_unlisted},
+    grep::{PathMatcher, grep},
+    infer::{format_order, infer_order},
+    order_command::OrderCommand,
+    preset::{PRESET_GROUP_NAME, Preset},
+    process::{process, report_warnings},
+    rustfmt::RustfmtConfig,
+    sort::{
+        Constraints, DeriveGroup, ItemKind, KindOrder, OrderPattern, PARTIAL_TRAIT_PAIRS, Reflow,
+        SortOptions, Style, Unlisted, normalize_path, read_derive_lists, sort, sort_stdin,
+    },
     util::parse_order,
 };
 
@@ -26,6 +39,9 @@ enum Cli {
 #[derive(Debug, Args)]
 #[command(version, about, long_about = None)]
 struct SortDerivesArgs {
+    #[command(subcommand)]
+    command: Option<SortDerivesCommand>,
+
     /// The path to the file to sort
     /// If not specified, all .rs files in the current directory will be sorted
     #[clap(short, long, value_name = "FILE", verbatim_doc_comment)]
@@ -36,6 +52,11 @@ struct SortDerivesArgs {
     #[clap(long, value_name = "VALUE", verbatim_doc_comment)]
     order: Option<String>,
 
+    /// Use a built-in order, which can be combined with --order
+    /// Derives listed in --order take precedence over the preset
+    #[clap(long, value_name = "NAME", verbatim_doc_comment)]
+    preset: Option<Preset>,
+
     /// Preserve the original order for unspecified derive attributes (only applies when --order is used)
     #[clap(long)]
     preserve: bool,
@@ -57,6 +78,12 @@ struct SortDerivesArgs {
     config: Option<String>,
 }
 
+#[derive(Debug, Subcommand)]
+enum SortDerivesCommand {
+    /// Print the order most consistent with how derives are written in the existing code
+    InferOrder,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum Color {
     Auto,
@@ -77,32 +104,306 @@ impl From<Color> for process::OutputColor {
 fn read_custom_order<'a>(
     config: &'a Config,
     args: &'a SortDerivesArgs,
+    groups: &HashMap<String, DeriveGroup>,
 ) -> Result<Option<Vec<String>>, String> {
     let order = args.order.clone().map(parse_order).or(config.order.clone());
+    complete_order(order, groups)
+}
+
+/// Validates the custom order, and puts the preset in it if specified.
+fn complete_order(
+    mut order: Option<Vec<String>>,
+    groups: &HashMap<String, DeriveGroup>,
+) -> Result<Option<Vec<String>>, String> {
     if let Some(order) = &order {
         if order.iter().filter(|s| *s == "...").count() > 1 {
             return Err("Only one '...' is allowed in the custom order".to_string());
         }
     }
+    if groups.contains_key(PRESET_GROUP_NAME) {
+        // the preset is placed at the end of the head unless its position is specified
+        let preset_ref = format!("@{PRESET_GROUP_NAME}");
+        let order = order.get_or_insert_with(Vec::new);
+        if !order.contains(&preset_ref) {
+            let i = order.iter().position(|s| s == "...").unwrap_or(order.len());
+            order.insert(i, preset_ref);
+        }
+    }
+    if let Some(order) = &order {
+        for s in order {
+            if let Some(name) = s.strip_prefix('@') {
+                let group = groups
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown group '{s}' in the custom order"))?;
+                for member in &group.derives {
+                    if member.starts_with('@') || member == "..." {
+                        return Err(format!("Group '{s}' cannot contain '{member}'"));
+                    }
+                    OrderPattern::parse(member)
+                        .map_err(|e| format!("Invalid pattern '{member}' in group '{s}': {e}"))?;
+                }
+            } else {
+                OrderPattern::parse(s)
+                    .map_err(|e| format!("Invalid pattern '{s}' in the custom order: {e}"))?;
+            }
+        }
+    }
     Ok(order)
 }
 
-fn read_preserve(config: &Config, args: &SortDerivesArgs) -> bool {
-    args.preserve || config.preserve.unwrap_or(false)
+fn read_kind_orders(
+    config: &Config,
+    groups: &HashMap<String, DeriveGroup>,
+    default: &KindOrder,
+) -> Result<HashMap<ItemKind, KindOrder>, String> {
+    let mut kind_orders = HashMap::new();
+    for (kind, kind_config) in config.kind.iter().flatten() {
+        let custom_order = match &kind_config.order {
+            Some(order) => complete_order(Some(order.clone()), groups)?,
+            None => default.custom_order.clone(),
+        };
+        let kind_order = KindOrder {
+            custom_order,
+            unlisted: resolve_unlisted(kind_config.unlisted, kind_config.preserve)
+                .unwrap_or(default.unlisted),
+        };
+        kind_orders.insert(*kind, kind_order);
+    }
+    Ok(kind_orders)
+}
+
+fn read_groups(config: &Config, args: &SortDerivesArgs) -> HashMap<String, DeriveGroup> {
+    let mut groups = config.groups.clone().unwrap_or_default();
+    if let Some(preset) = args.preset.or(config.preset) {
+        groups.insert(
+            PRESET_GROUP_NAME.to_string(),
+            DeriveGroup {
+                derives: preset.order(),
+                ..Default::default()
+            },
+        );
+    }
+    groups
+}
+
+fn read_constraints(config: &Config) -> Result<Option<Constraints>, String> {
+    config
+        .constraints
+        .as_deref()
+        .map(Constraints::new)
+        .transpose()
+}
+
+fn read_pairs(config: &Config) -> Vec<(String, String)> {
+    let partial_trait_pairs = PARTIAL_TRAIT_PAIRS
+        .iter()
+        .filter(|_| config.pair_partial_traits.unwrap_or(false))
+        .map(|(partial, total)| (partial.to_string(), total.to_string()));
+    partial_trait_pairs
+        .chain(config.pairs.clone().unwrap_or_default())
+        .collect()
+}
+
+fn read_default_unlisted(config: &Config, args: &SortDerivesArgs) -> Unlisted {
+    if args.preserve {
+        return Unlisted::Preserve;
+    }
+    resolve_unlisted(config.unlisted, config.preserve).unwrap_or_default()
+}
+
+/// Whether derives are placed by their frequency anywhere in the config.
+fn uses_frequency(config: &Config) -> bool {
+    let kind_unlisted = config.kind.iter().flatten().map(|(_, k)| k.unlisted);
+    let override_uses_frequency = config
+        .overrides
+        .iter()
+        .flatten()
+        .any(|o| uses_frequency(&o.config));
+    std::iter::once(config.unlisted)
+        .chain(kind_unlisted)
+        .any(|unlisted| unlisted == Some(Unlisted::ByFrequency))
+        || override_uses_frequency
+}
+
+/// Counts how many times each derive is written in the files in the current directory.
+fn read_frequencies(config: &Config) -> Result<HashMap<String, usize>, Box<dyn std::error::Error>> {
+    let mut frequencies = HashMap::new();
+    let include_comments = read_include_comments(config);
+    for (file_path, line_numbers) in grep(None::<&str>, read_exclude(config))? {
+        for derive_list in read_derive_lists(&file_path, line_numbers, include_comments)?.names {
+            for name in derive_list {
+                *frequencies.entry(name).or_default() += 1;
+            }
+        }
+    }
+    Ok(frequencies)
+}
+
+fn read_aliases(config: &Config) -> HashMap<String, String> {
+    config
+        .aliases
+        .iter()
+        .flatten()
+        .map(|(path, canonical)| (normalize_path(path), normalize_path(canonical)))
+        .collect()
+}
+
+/// Order commands started for the config, shared by the files with the same command.
+type OrderCommands = HashMap<Vec<String>, Rc<OrderCommand>>;
+
+fn read_order_command(
+    config: &Config,
+    order_commands: &mut OrderCommands,
+) -> Result<Option<Rc<OrderCommand>>, String> {
+    let Some(argv) = &config.order_command else {
+        return Ok(None);
+    };
+    if let Some(order_command) = order_commands.get(argv) {
+        return Ok(Some(order_command.clone()));
+    }
+    let order_command = Rc::new(OrderCommand::new(argv.clone())?);
+    order_commands.insert(argv.clone(), order_command.clone());
+    Ok(Some(order_command))
+}
+
+fn read_include_comments(config: &Config) -> bool {
+    config.include_comments.unwrap_or(false)
+}
+
+fn read_style(config: &Config) -> Style {
+    config.style.unwrap_or_default()
+}
+
+fn read_reflow(config: &Config, args: &SortDerivesArgs) -> Option<Reflow> {
+    config.reflow.unwrap_or(false).then(|| Reflow {
+        // rustfmt config is read from the same directory as the config file
+        rustfmt: RustfmtConfig::load(&Config::dir(&args.config)),
+        vertical_threshold: config.vertical_threshold,
+    })
 }
 
 fn read_exclude(config: &Config) -> Vec<String> {
     config.exclude.clone().unwrap_or_default()
 }
 
+fn read_options(
+    config: &Config,
+    args: &SortDerivesArgs,
+    frequencies: &HashMap<String, usize>,
+    order_commands: &mut OrderCommands,
+) -> Result<SortOptions, String> {
+    let groups = read_groups(config, args);
+    let default_order = KindOrder {
+        custom_order: read_custom_order(config, args, &groups)?,
+        unlisted: read_default_unlisted(config, args),
+    };
+    let kind_orders = read_kind_orders(config, &groups, &default_order)?;
+    Ok(SortOptions {
+        custom_order: default_order.custom_order,
+        unlisted: default_order.unlisted,
+        include_comments: read_include_comments(config),
+        style: read_style(config),
+        trailing_comma: config.trailing_comma,
+        reflow: read_reflow(config, args),
+        merge: config.merge.unwrap_or(false),
+        split: config.split.clone(),
+        dedupe: config.dedupe.unwrap_or(false),
+        groups,
+        constraints: read_constraints(config)?,
+        pairs: read_pairs(config),
+        kind_orders,
+        sort_mode: config.sort_mode.unwrap_or_default(),
+        frequencies: frequencies.clone(),
+        aliases: read_aliases(config),
+        order_command: read_order_command(config, order_commands)?,
+    })
+}
+
+struct Override<'a> {
+    files: PathMatcher,
+    exclude: Option<PathMatcher>,
+    config: &'a Config,
+}
+
+fn read_overrides(config: &Config) -> Result<Vec<Override<'_>>, String> {
+    config
+        .overrides
+        .iter()
+        .flatten()
+        .map(|o| {
+            Ok(Override {
+                files: PathMatcher::new(&o.files)?,
+                exclude: o
+                    .config
+                    .exclude
+                    .as_deref()
+                    .map(PathMatcher::new)
+                    .transpose()?,
+                config: &o.config,
+            })
+        })
+        .collect()
+}
+
+fn infer(config: &Config, args: &SortDerivesArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let include_comments = read_include_comments(config);
+    let files = grep(args.path.clone(), read_exclude(config))?;
+    let mut derive_lists = Vec::new();
+    let mut total = 0;
+    for (file_path, line_numbers) in &files {
+        let read = read_derive_lists(file_path, line_numbers.clone(), include_comments)?;
+        derive_lists.extend(read.names);
+        total += read.lines;
+    }
+
+    let order = infer_order(&derive_lists);
+    let options = SortOptions {
+        custom_order: Some(order.clone()),
+        include_comments,
+        style: Style::Preserve,
+        ..Default::default()
+    };
+    let mut changed = 0;
+    for (file_path, line_numbers) in files {
+        // derives are only reordered with the preserved style, so the lines stay aligned
+        let (old_lines, new_lines, _) = sort(&file_path, line_numbers, &options)?;
+        changed += old_lines
+            .iter()
+            .zip(&new_lines)
+            .filter(|(old, new)| old != new)
+            .count();
+    }
+
+    print!("{}", format_order(&order));
+    let percentage = if total == 0 {
+        0.0
+    } else {
+        changed as f64 * 100.0 / total as f64
+    };
+    println!(
+        "# {changed} of {total} lines with derives ({percentage:.1}%) would change with this order"
+    );
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let Cli::SortDerives(args) = Cli::parse();
     let config = Config::load(&args.config);
 
-    let custom_order = read_custom_order(&config, &args)?;
-    let preserve = read_preserve(&config, &args);
+    if let Some(SortDerivesCommand::InferOrder) = args.command {
+        return infer(&config, &args);
+    }
+
+    let frequencies = if uses_frequency(&config) {
+        read_frequencies(&config)?
+    } else {
+        HashMap::new()
+    };
+    let mut order_commands = OrderCommands::new();
+    let options = read_options(&config, &args, &frequencies, &mut order_commands)?;
+    let overrides = read_overrides(&config)?;
     let exclude = read_exclude(&config);
-    let path = args.path;
+    let path = args.path.clone();
     let check = args.check;
     let stdin = args.stdin;
     let output_color = args.color.into();
@@ -111,17 +412,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut input = String::new();
         std::io::stdin().read_to_string(&mut input)?;
         // stdin input is already the whole target, so file discovery via grep is not needed.
-        let (old_lines, new_lines) = sort_stdin(&input, &custom_order, preserve)?;
+        // process only uses this path when rendering check diffs and warnings.
+        let stdin_path = Path::new("<stdin>");
+        let (old_lines, new_lines, warnings) = match sort_stdin(&input, &options) {
+            Ok(sorted) => sorted,
+            Err(e) => {
+                eprintln!("error: {}: {e}", stdin_path.display());
+                std::process::exit(1);
+            }
+        };
+        let no_warning = report_warnings(stdin_path, &warnings, check, output_color);
 
         if check {
-            if !process(
-                // process only uses this path when rendering check diffs.
-                Path::new("<stdin>"),
-                old_lines,
-                new_lines,
-                true,
-                output_color,
-            )? {
+            if !process(stdin_path, old_lines, new_lines, true, output_color)? || !no_warning {
                 std::process::exit(1);
             }
         } else {
@@ -131,9 +434,51 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // exclusions are ignored if the path is specified, as well as the top-level ones
+    let use_exclude = path.is_none();
+    // options for each set of overrides matching a file
+    let mut override_options: HashMap<Vec<usize>, SortOptions> = HashMap::new();
     let mut no_diff = true;
     for (file_path, line_numbers) in grep(path, exclude)? {
-        let (old_lines, new_lines) = sort(&file_path, line_numbers, &custom_order, preserve)?;
+        let matched: Vec<usize> = (0..overrides.len())
+            .filter(|i| overrides[*i].files.is_match(&file_path))
+            .collect();
+        let excluded = matched.iter().any(|i| {
+            overrides[*i]
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(&file_path))
+        });
+        if use_exclude && excluded {
+            continue;
+        }
+
+        let options = if matched.is_empty() {
+            &options
+        } else {
+            if !override_options.contains_key(&matched) {
+                // later overrides take precedence
+                let file_config = matched
+                    .iter()
+                    .fold(config.clone(), |c, i| c.merge(overrides[*i].config));
+                override_options.insert(
+                    matched.clone(),
+                    read_options(&file_config, &args, &frequencies, &mut order_commands)?,
+                );
+            }
+            &override_options[&matched]
+        };
+
+        // an error in one file, e.g. from the order command, does not stop the others
+        let (old_lines, new_lines, warnings) = match sort(&file_path, line_numbers, options) {
+            Ok(sorted) => sorted,
+            Err(e) => {
+                eprintln!("error: {}: {e}", file_path.display());
+                no_diff = false;
+                continue;
+            }
+        };
+        no_diff &= report_warnings(&file_path, &warnings, check, output_color);
         no_diff &= process(&file_path, old_lines, new_lines, check, output_color)?;
     }
 
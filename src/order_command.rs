@@ -0,0 +1,236 @@
+use std::{
+    cell::RefCell,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::sort::ItemKind;
+
+/// How long the command may take to answer a derive list before it is stopped.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An external command that decides the order of derive lists.
+/// The command is started when it is first used, and keeps running to answer every derive list.
+#[derive(Debug)]
+pub struct OrderCommand {
+    argv: Vec<String>,
+    timeout: Duration,
+    process: RefCell<Option<Process>>,
+}
+
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines read from the stdout of the command, read on another thread so that waiting for them can time out
+    responses: Receiver<std::io::Result<String>>,
+}
+
+/// A derive list sent to the command as one line of JSON.
+#[derive(Debug, Serialize)]
+pub struct OrderRequest<'a> {
+    pub file: &'a str,
+    pub item: Option<&'a str>,
+    pub kind: Option<ItemKind>,
+    pub derives: Vec<String>,
+}
+
+impl OrderCommand {
+    pub fn new(argv: Vec<String>) -> Result<OrderCommand, String> {
+        if argv.is_empty() {
+            return Err("order_command must not be empty".into());
+        }
+        Ok(OrderCommand {
+            argv,
+            timeout: TIMEOUT,
+            process: RefCell::new(None),
+        })
+    }
+
+    /// Returns the permutation answered by the command, where the i-th element is the index of the derive placed at i.
+    /// If the command exits or does not answer in time, it is started again for the next request.
+    pub fn order(&self, request: &OrderRequest) -> Result<Vec<usize>, String> {
+        let mut process = self.process.borrow_mut();
+        if process.is_none() {
+            *process = Some(self.spawn()?);
+        }
+
+        let mut line = serde_json::to_string(request).unwrap();
+        line.push('\n');
+        let running = process.as_mut().unwrap();
+        let response = exchange(running, &line, self.timeout);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                let mut stopped = process.take().unwrap();
+                let _ = stopped.child.kill();
+                let _ = stopped.child.wait();
+                return Err(format!(
+                    "order command did not answer within {:?}",
+                    self.timeout
+                ));
+            }
+            Err(e) => {
+                let mut stopped = process.take().unwrap();
+                drop(stopped.stdin);
+                let status = match stopped.child.wait() {
+                    Ok(status) => status.to_string(),
+                    Err(_) => e.to_string(),
+                };
+                return Err(format!("order command exited unexpectedly ({status})"));
+            }
+        };
+
+        parse_permutation(&response, request.derives.len())
+    }
+
+    fn spawn(&self) -> Result<Process, String> {
+        let mut child = Command::new(&self.argv[0])
+            .args(&self.argv[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to run order command `{}`: {e}", self.argv.join(" ")))?;
+        let stdin = child.stdin.take().unwrap();
+        let responses = read_lines(child.stdout.take().unwrap());
+        Ok(Process {
+            child,
+            stdin,
+            responses,
+        })
+    }
+}
+
+/// Reads the lines of `stdout` on a new thread until it is closed or fails.
+fn read_lines(stdout: ChildStdout) -> Receiver<std::io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        loop {
+            let mut line = String::new();
+            let result = stdout.read_line(&mut line).map(|_| line);
+            let closed = !matches!(&result, Ok(line) if !line.is_empty());
+            if sender.send(result).is_err() || closed {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn exchange(process: &mut Process, line: &str, timeout: Duration) -> std::io::Result<String> {
+    process.stdin.write_all(line.as_bytes())?;
+    process.stdin.flush()?;
+    match process.responses.recv_timeout(timeout) {
+        Ok(Ok(response)) if !response.is_empty() => Ok(response),
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(_)) | Err(RecvTimeoutError::Disconnected) => {
+            Err(std::io::ErrorKind::UnexpectedEof.into())
+        }
+        Err(RecvTimeoutError::Timeout) => Err(std::io::ErrorKind::TimedOut.into()),
+    }
+}
+
+fn parse_permutation(response: &str, len: usize) -> Result<Vec<usize>, String> {
+    let response = response.trim();
+    let permutation: Vec<usize> = serde_json::from_str(response)
+        .map_err(|e| format!("invalid response from order command `{response}`: {e}"))?;
+    let mut seen = vec![false; len];
+    let valid = permutation.len() == len
+        && permutation.iter().all(|i| {
+            seen.get_mut(*i)
+                .is_some_and(|s| !std::mem::replace(s, true))
+        });
+    if !valid {
+        return Err(format!(
+            "order command returned `{response}`, which is not a permutation of {len} derives"
+        ));
+    }
+    Ok(permutation)
+}
+
+impl Drop for OrderCommand {
+    fn drop(&mut self) {
+        // closing stdin tells the command that there are no more requests
+        if let Some(Process {
+            mut child, stdin, ..
+        }) = self.process.take()
+        {
+            drop(stdin);
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(derives: &[&str]) -> OrderRequest<'static> {
+        OrderRequest {
+            file: "a.rs",
+            item: Some("A"),
+            kind: Some(ItemKind::Struct),
+            derives: derives.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    fn sh(script: &str) -> OrderCommand {
+        OrderCommand::new(vec!["sh".into(), "-c".into(), script.into()]).unwrap()
+    }
+
+    #[test]
+    fn test_order() {
+        // answers the number of requests received so far, so that the process is known to be reused
+        let command = sh(r#"n=0; while read -r line; do n=$((n + 1)); echo "[$n, 0]"; done"#);
+
+        assert_eq!(command.order(&request(&["A", "B"])), Ok(vec![1, 0]));
+        assert_eq!(
+            command.order(&request(&["A", "B"])),
+            Err("order command returned `[2, 0]`, which is not a permutation of 2 derives".into())
+        );
+    }
+
+    #[test]
+    fn test_order_request() {
+        let actual = serde_json::to_string(&request(&["Debug", "serde::Serialize"])).unwrap();
+        assert_eq!(
+            actual,
+            r#"{"file":"a.rs","item":"A","kind":"struct","derives":["Debug","serde::Serialize"]}"#
+        );
+    }
+
+    #[test]
+    fn test_order_exited() {
+        let command = sh("read -r line; exit 3");
+
+        assert_eq!(
+            command.order(&request(&["A", "B"])),
+            Err("order command exited unexpectedly (exit status: 3)".into())
+        );
+    }
+
+    #[test]
+    fn test_order_timed_out() {
+        let mut command = sh("read -r line; exec sleep 10");
+        command.timeout = Duration::from_millis(200);
+
+        assert_eq!(
+            command.order(&request(&["A", "B"])),
+            Err("order command did not answer within 200ms".into())
+        );
+    }
+
+    #[test]
+    fn test_parse_permutation() {
+        assert_eq!(parse_permutation("[2, 0, 1]\n", 3), Ok(vec![2, 0, 1]));
+        assert!(parse_permutation("[0, 0, 1]", 3).is_err());
+        assert!(parse_permutation("[0, 1]", 3).is_err());
+        assert!(parse_permutation("[0, 1, 3]", 3).is_err());
+        assert!(parse_permutation("0, 1, 2", 3).is_err());
+    }
+}
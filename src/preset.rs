@@ -0,0 +1,58 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// The name of the group the preset is expanded to, which can be referenced as `@preset` in the custom order.
+pub const PRESET_GROUP_NAME: &str = "preset";
+
+/// Built-in orders of the derives in the standard library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// `Debug` and `Default`, then the other standard derives in the order they build on each other
+    StdFirst,
+    /// The order of the common traits in the Rust API Guidelines (C-COMMON-TRAITS)
+    ApiGuidelines,
+    /// Supertraits before their subtraits
+    CompilerDependency,
+}
+
+impl Preset {
+    pub fn order(self) -> Vec<String> {
+        let order: &[&str] = match self {
+            Preset::StdFirst => &[
+                "Debug",
+                "Default",
+                "Clone",
+                "Copy",
+                "PartialEq",
+                "Eq",
+                "PartialOrd",
+                "Ord",
+                "Hash",
+            ],
+            Preset::ApiGuidelines => &[
+                "Copy",
+                "Clone",
+                "Eq",
+                "PartialEq",
+                "Ord",
+                "PartialOrd",
+                "Hash",
+                "Debug",
+                "Default",
+            ],
+            Preset::CompilerDependency => &[
+                "Clone",
+                "Copy",
+                "PartialEq",
+                "Eq",
+                "PartialOrd",
+                "Ord",
+                "Hash",
+                "Debug",
+                "Default",
+            ],
+        };
+        order.iter().map(|s| s.to_string()).collect()
+    }
+}
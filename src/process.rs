@@ -3,6 +3,8 @@ use std::path::Path;
 use console::Style;
 use similar::{ChangeTag, TextDiff};
 
+use crate::sort::Warning;
+
 #[derive(Debug, Clone, Copy)]
 pub enum OutputColor {
     Auto,
@@ -38,6 +40,38 @@ pub fn process(
     Ok(false)
 }
 
+/// Prints the problems found in the file.
+/// When checking, all of them are printed and the check fails. Otherwise, only the ones not fixed are printed.
+pub fn report_warnings(
+    file_path: &Path,
+    warnings: &[Warning],
+    check: bool,
+    output_color: OutputColor,
+) -> bool {
+    let (_, del_style, _) = output_style(output_color);
+    let mut ok = true;
+    for warning in warnings {
+        if check {
+            let line = format!(
+                "{}:{}: {}\n",
+                file_path.display(),
+                warning.line,
+                warning.message
+            );
+            print!("{}", del_style.apply_to(line));
+            ok = false;
+        } else if !warning.fixed {
+            eprintln!(
+                "warning: {}:{}: {}",
+                file_path.display(),
+                warning.line,
+                warning.message
+            );
+        }
+    }
+    ok
+}
+
 fn write_file(file_path: &Path, new_lines: Vec<String>) -> Result<(), std::io::Error> {
     std::fs::write(file_path, new_lines.concat())
 }
@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+const RUSTFMT_CONFIG_FILE_NAMES: &[&str] = &[".rustfmt.toml", "rustfmt.toml"];
+
+/// The subset of the rustfmt config that affects how derive lists are wrapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RustfmtConfig {
+    pub max_width: usize,
+    pub tab_spaces: usize,
+    pub hard_tabs: bool,
+    pub use_small_heuristics: Heuristics,
+    /// Overrides the width given by `use_small_heuristics` if specified
+    pub attr_fn_like_width: Option<usize>,
+}
+
+/// rustfmt's `use_small_heuristics`, which decides the default widths of the smaller constructs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Heuristics {
+    /// Scaled with `max_width` above 100
+    #[default]
+    Default,
+    /// No limits other than `max_width`
+    Off,
+    /// As wide as `max_width`
+    Max,
+}
+
+impl Default for RustfmtConfig {
+    fn default() -> Self {
+        // rustfmt's defaults
+        RustfmtConfig {
+            max_width: 100,
+            tab_spaces: 4,
+            hard_tabs: false,
+            use_small_heuristics: Heuristics::Default,
+            attr_fn_like_width: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct InternalRustfmtConfig {
+    max_width: Option<usize>,
+    tab_spaces: Option<usize>,
+    hard_tabs: Option<bool>,
+    use_small_heuristics: Option<Heuristics>,
+    attr_fn_like_width: Option<usize>,
+}
+
+impl From<InternalRustfmtConfig> for RustfmtConfig {
+    fn from(internal_config: InternalRustfmtConfig) -> Self {
+        let default = RustfmtConfig::default();
+        RustfmtConfig {
+            max_width: internal_config.max_width.unwrap_or(default.max_width),
+            tab_spaces: internal_config.tab_spaces.unwrap_or(default.tab_spaces),
+            hard_tabs: internal_config.hard_tabs.unwrap_or(default.hard_tabs),
+            use_small_heuristics: internal_config
+                .use_small_heuristics
+                .unwrap_or(default.use_small_heuristics),
+            attr_fn_like_width: internal_config.attr_fn_like_width,
+        }
+    }
+}
+
+impl RustfmtConfig {
+    /// Loads `.rustfmt.toml` or `rustfmt.toml` in `dir`, or returns the default config if neither exists.
+    pub fn load(dir: &Path) -> RustfmtConfig {
+        let path = RUSTFMT_CONFIG_FILE_NAMES
+            .iter()
+            .map(|p| dir.join(p))
+            .find(|p| p.exists());
+        if let Some(path) = path {
+            let config_file = std::fs::read_to_string(path).unwrap();
+            let internal_config: InternalRustfmtConfig = toml::from_str(&config_file).unwrap();
+            internal_config.into()
+        } else {
+            RustfmtConfig::default()
+        }
+    }
+
+    /// The string used for one level of indentation.
+    pub fn indent_unit(&self) -> String {
+        if self.hard_tabs {
+            "\t".into()
+        } else {
+            " ".repeat(self.tab_spaces)
+        }
+    }
+
+    /// rustfmt's `attr_fn_like_width`, the maximum width of the arguments of a function-like attribute
+    /// on one line. An explicit value is limited to `max_width`.
+    pub fn attr_fn_like_width(&self) -> usize {
+        if let Some(width) = self.attr_fn_like_width {
+            return width.min(self.max_width);
+        }
+        match self.use_small_heuristics {
+            Heuristics::Default => {
+                // rustfmt rounds the ratio to the closest 0.1
+                let ratio = if self.max_width > 100 {
+                    (self.max_width as f32 / 100.0 * 10.0).round() / 10.0
+                } else {
+                    1.0
+                };
+                (70.0 * ratio).round() as usize
+            }
+            Heuristics::Off => usize::MAX,
+            Heuristics::Max => self.max_width,
+        }
+    }
+
+    /// The width of `s` as rustfmt counts it, where a tab is `tab_spaces` wide.
+    pub fn width(&self, s: &str) -> usize {
+        s.chars()
+            .map(|c| if c == '\t' { self.tab_spaces } else { 1 })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::prelude::{FileWriteStr, PathChild};
+
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        dir.child("rustfmt.toml")
+            .write_str("max_width = 80\nhard_tabs = true\nedition = \"2021\"\nuse_small_heuristics = \"Max\"\n")
+            .unwrap();
+
+        let actual = RustfmtConfig::load(dir.path());
+
+        let expected = RustfmtConfig {
+            max_width: 80,
+            tab_spaces: 4,
+            hard_tabs: true,
+            use_small_heuristics: Heuristics::Max,
+            attr_fn_like_width: None,
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_attr_fn_like_width() {
+        let cases = [
+            (100, Heuristics::Default, None, 70),
+            (60, Heuristics::Default, None, 70),
+            // the ratio 1.15 is rounded to 1.2
+            (115, Heuristics::Default, None, 84),
+            (144, Heuristics::Default, None, 98),
+            (80, Heuristics::Max, None, 80),
+            (80, Heuristics::Off, None, usize::MAX),
+            (115, Heuristics::Default, Some(50), 50),
+            (80, Heuristics::Off, Some(90), 80),
+        ];
+        for (max_width, use_small_heuristics, attr_fn_like_width, expected) in cases {
+            let config = RustfmtConfig {
+                max_width,
+                use_small_heuristics,
+                attr_fn_like_width,
+                ..Default::default()
+            };
+            assert_eq!(
+                config.attr_fn_like_width(),
+                expected,
+                "{max_width}, {use_small_heuristics:?}, {attr_fn_like_width:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_hidden_file() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        dir.child(".rustfmt.toml")
+            .write_str("tab_spaces = 2\n")
+            .unwrap();
+
+        let actual = RustfmtConfig::load(dir.path());
+
+        let expected = RustfmtConfig {
+            tab_spaces: 2,
+            ..Default::default()
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_load_not_exist() {
+        let dir = assert_fs::TempDir::new().unwrap();
+
+        let actual = RustfmtConfig::load(dir.path());
+
+        assert_eq!(actual, RustfmtConfig::default());
+    }
+}
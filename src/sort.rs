@@ -1,72 +1,509 @@
 use std::{
     collections::{HashMap, HashSet},
     io::BufRead,
+    ops::Range,
     path::Path,
+    rc::Rc,
     sync::LazyLock,
 };
 
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-use crate::ext::BufReadExt;
+use crate::{
+    ext::BufReadExt,
+    imports::{Imports, Resolution},
+    lexer::CodeMap,
+    order_command::{OrderCommand, OrderRequest},
+    rustfmt::RustfmtConfig,
+};
 
-const DERIVE_PATTERN: &str = r"#\[derive\(\s*([^\)]+?)\s*\)\]";
-const CFG_ATTR_PATTERN: &str = r"#\[cfg_attr\((.+),\s*derive\(\s*([^\)]+?)\s*\)\)\]";
+const ATTR_START_PATTERN: &str = r"#\[(?:derive|cfg_attr)\(";
 
-static DERIVE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(DERIVE_PATTERN).unwrap());
-static CFG_ATTR_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(CFG_ATTR_PATTERN).unwrap());
+static ATTR_START_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(ATTR_START_PATTERN).unwrap());
 
 const DISABLE_NEXT_LINE: &str = "sort-derives-disable-next-line";
 const DISABLE_START: &str = "sort-derives-disable-start";
 const DISABLE_END: &str = "sort-derives-disable-end";
 
+#[derive(Debug, Default)]
+pub struct SortOptions {
+    pub custom_order: Option<Vec<String>>,
+    pub unlisted: Unlisted,
+    pub include_comments: bool,
+    pub style: Style,
+    pub trailing_comma: Option<TrailingComma>,
+    pub reflow: Option<Reflow>,
+    pub merge: bool,
+    pub split: Option<Vec<Vec<String>>>,
+    pub dedupe: bool,
+    pub groups: HashMap<String, DeriveGroup>,
+    pub constraints: Option<Constraints>,
+    /// Pairs of derives kept adjacent, the first one right before the second one
+    pub pairs: Vec<(String, String)>,
+    pub kind_orders: HashMap<ItemKind, KindOrder>,
+    pub sort_mode: SortMode,
+    /// The number of times each derive is written in the files, used for `Unlisted::ByFrequency`
+    pub frequencies: HashMap<String, usize>,
+    /// Canonical paths of derives written with other paths, e.g. re-exports
+    pub aliases: HashMap<String, String>,
+    /// An external command deciding the order instead of the settings above
+    pub order_command: Option<Rc<OrderCommand>>,
+}
+
+/// Wrap derive lists the way rustfmt does.
+#[derive(Debug, Default, Clone)]
+pub struct Reflow {
+    pub rustfmt: RustfmtConfig,
+    /// Derive lists with more derives than this are always written one per line
+    pub vertical_threshold: Option<usize>,
+}
+
+/// How the separators in a derive list are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Style {
+    /// Keep the spacing around the commas as written in the source
+    Preserve,
+    /// Separate derives with `", "` (vertical lists keep one derive per line)
+    #[default]
+    Normalize,
+}
+
+/// The pairs of a partial trait and its total trait.
+pub const PARTIAL_TRAIT_PAIRS: &[(&str, &str)] = &[("PartialEq", "Eq"), ("PartialOrd", "Ord")];
+
+/// A named set of derives that can be referenced as `@name` in the custom order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeriveGroup {
+    /// Derive names, paths or patterns
+    pub derives: Vec<String>,
+    pub order: GroupOrder,
+}
+
+/// How the derives in a group are ordered.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupOrder {
+    /// In the order they are listed in the group
+    #[default]
+    Listed,
+    Alphabetical,
+    /// Keep the original order
+    Preserve,
+}
+
+/// How the derives not in the custom order are placed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unlisted {
+    #[default]
+    Alphabetical,
+    /// Keep the original order
+    Preserve,
+    /// Grouped by the first segment of the resolved path (unresolved derives last), then alphabetically
+    ByCrate,
+    /// The most frequently written derive in the files first, then alphabetically
+    ByFrequency,
+}
+
+/// How derive names are compared when they are sorted alphabetically.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortMode {
+    /// By bytes, so uppercase letters come before lowercase ones
+    #[default]
+    Lexical,
+    CaseInsensitive,
+    /// Numbers in names are compared by their values, e.g. `Foo2` before `Foo10`
+    Natural,
+}
+
+impl SortMode {
+    fn compare(self, a: &str, b: &str) -> std::cmp::Ordering {
+        match self {
+            SortMode::Lexical => a.cmp(b),
+            SortMode::CaseInsensitive => a
+                .to_lowercase()
+                .cmp(&b.to_lowercase())
+                .then_with(|| a.cmp(b)),
+            SortMode::Natural => natural_chunks(a)
+                .cmp(natural_chunks(b))
+                .then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+/// A run of digits or non-digits in a name, ordered as `natural` sort mode compares them.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum NaturalChunk<'a> {
+    /// The number of significant digits and the digits, so that the values are compared
+    Number(usize, &'a str),
+    Text(&'a str),
+}
+
+fn natural_chunks(s: &str) -> impl Iterator<Item = NaturalChunk<'_>> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let is_digit = first.is_ascii_digit();
+        let len = rest
+            .find(|c: char| c.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+        let (chunk, next) = rest.split_at(len);
+        rest = next;
+        if is_digit {
+            let digits = chunk.trim_start_matches('0');
+            Some(NaturalChunk::Number(digits.len(), digits))
+        } else {
+            Some(NaturalChunk::Text(chunk))
+        }
+    })
+}
+
+/// The kind of the item that derives are written on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemKind {
+    Struct,
+    Enum,
+    Union,
+}
+
+/// The order used for derives on a specific kind of item instead of the default one.
+#[derive(Debug, Default, Clone)]
+pub struct KindOrder {
+    pub custom_order: Option<Vec<String>>,
+    pub unlisted: Unlisted,
+}
+
+/// Whether a derive list ends with a comma.
+/// If not specified, single-line lists follow `Style`, and vertical lists are kept as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrailingComma {
+    Preserve,
+    Always,
+    Never,
+}
+
+/// The lines before and after sorting, and the problems found in them.
+pub type Sorted = (Vec<String>, Vec<String>, Vec<Warning>);
+
+/// A problem found in a derive attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub line: usize,
+    pub message: String,
+    /// Whether the problem is fixed in the sorted lines
+    pub fixed: bool,
+}
+
 pub fn sort(
     file_path: &Path,
     line_numbers: HashSet<usize>,
-    custom_order: &Option<Vec<String>>,
-    preserve: bool,
-) -> Result<(Vec<String>, Vec<String>), std::io::Error> {
+    options: &SortOptions,
+) -> Result<Sorted, std::io::Error> {
     let file = std::fs::File::open(file_path)?;
     let reader = std::io::BufReader::new(file);
-    sort_reader(reader, Some(&line_numbers), custom_order, preserve)
+    let file_name = file_path.display().to_string();
+    sort_reader(reader, &file_name, Some(&line_numbers), options)
 }
 
-pub fn sort_stdin(
-    input: &str,
-    custom_order: &Option<Vec<String>>,
-    preserve: bool,
-) -> Result<(Vec<String>, Vec<String>), std::io::Error> {
+pub fn sort_stdin(input: &str, options: &SortOptions) -> Result<Sorted, std::io::Error> {
     let reader = std::io::Cursor::new(input);
-    sort_reader(reader, None, custom_order, preserve)
+    sort_reader(reader, "<stdin>", None, options)
+}
+
+/// The derive lists of a file, as read by `read_derive_lists`.
+#[derive(Debug, Default)]
+pub struct DeriveLists {
+    /// The base names of the derives in each derive list
+    pub names: Vec<Vec<String>>,
+    /// The number of lines the attributes with derive lists are written on
+    pub lines: usize,
+}
+
+/// Reads the derive lists of the file, skipping the disabled ones.
+pub fn read_derive_lists(
+    file_path: &Path,
+    line_numbers: HashSet<usize>,
+    include_comments: bool,
+) -> Result<DeriveLists, std::io::Error> {
+    let text = std::fs::read_to_string(file_path)?;
+    let lines: Vec<String> = split_lines(&text).collect();
+    let code_map = if include_comments {
+        CodeMap::default()
+    } else {
+        CodeMap::new(&text)
+    };
+
+    let mut derive_lists = DeriveLists::default();
+    let mut line_start = 0;
+    // offset up to which has been read, as an attribute may span multiple lines
+    let mut read = 0;
+    for (line, sortable) in lines
+        .iter()
+        .zip(sortable_lines(&lines, Some(&line_numbers)))
+    {
+        let line_range = line_start..line_start + line.len();
+        line_start += line.len();
+        if !sortable {
+            continue;
+        }
+        for attr_range in find_attributes(&text, line_range, &code_map) {
+            if attr_range.start < read {
+                continue;
+            }
+            read = attr_range.end;
+            let attr = &text[attr_range];
+            let parsed = parse_derive_lists(attr);
+            if parsed.is_empty() {
+                continue;
+            }
+            derive_lists.lines += attr.matches('\n').count() + 1;
+            derive_lists
+                .names
+                .extend(parsed.into_iter().map(|derive_list| {
+                    derive_list
+                        .derives
+                        .into_iter()
+                        .map(|d| d.base_name)
+                        .collect()
+                }));
+        }
+    }
+    Ok(derive_lists)
 }
 
 fn sort_reader<R: BufRead>(
     reader: R,
+    file_name: &str,
     line_numbers: Option<&HashSet<usize>>,
-    custom_order: &Option<Vec<String>>,
-    preserve: bool,
-) -> Result<(Vec<String>, Vec<String>), std::io::Error> {
-    let capacity = line_numbers.map_or(0, HashSet::len);
-    let mut old_lines = Vec::with_capacity(capacity);
-    let mut new_lines = Vec::with_capacity(capacity);
+    options: &SortOptions,
+) -> Result<Sorted, std::io::Error> {
+    let old_lines = reader
+        .lines_with_terminator()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let text = old_lines.concat();
+    let code_map = if options.include_comments {
+        CodeMap::default()
+    } else {
+        CodeMap::new(&text)
+    };
+    // `use` declarations in comments are never read, even if derives in comments are sorted
+    let imports = if options.include_comments {
+        Imports::new(&text, &CodeMap::new(&text))
+    } else {
+        Imports::new(&text, &code_map)
+    };
+    let sortable_lines = sortable_lines(&old_lines, line_numbers);
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(old_lines.iter().scan(0, |offset, line| {
+            *offset += line.len();
+            Some(*offset)
+        }))
+        .collect();
+    let line_index = |offset: usize| line_starts.partition_point(|start| *start <= offset) - 1;
+    let is_sortable = |offset: usize| {
+        sortable_lines
+            .get(line_index(offset))
+            .copied()
+            .unwrap_or(false)
+    };
+
+    let mut new_text = String::with_capacity(text.len());
+    // offset of `text` up to which has been copied to `new_text`
+    let mut copied = 0;
+    // ranges of the attributes merged into a preceding one
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    // start offsets of the attributes following the current `#[derive(...)]` on the same item,
+    // and the derives already written in them
+    let mut siblings: Vec<usize> = Vec::new();
+    let mut seen_derives: HashSet<String> = HashSet::new();
+    let mut warnings = Vec::new();
+
+    for (i, line) in old_lines.iter().enumerate() {
+        if !sortable_lines[i] {
+            continue;
+        }
+        let line_range = line_starts[i]..line_starts[i] + line.len();
+        for attr_range in find_attributes(&text, line_range, &code_map) {
+            if attr_range.start < copied {
+                // inside an attribute that has already been replaced
+                continue;
+            }
+            if let Some(removed) = merged.iter().find(|r| r.contains(&attr_range.start)) {
+                new_text.push_str(&text[copied..removed.start]);
+                copied = removed.end;
+                continue;
+            }
+
+            let attr = &text[attr_range.clone()];
+            let mut derive_lists = parse_derive_lists(attr);
+            if derive_lists.is_empty() {
+                continue;
+            }
+            let plain_derive = is_plain_derive(attr, &derive_lists);
+            if options.merge && plain_derive {
+                // derives are never merged across `cfg_attr`
+                for next_range in following_attributes(&text, attr_range.end, &code_map) {
+                    let next_attr = &text[next_range.clone()];
+                    if next_attr.starts_with("#[cfg_attr") || !is_sortable(next_range.start) {
+                        break;
+                    }
+                    let mut next_derive_lists = parse_derive_lists(next_attr);
+                    if is_plain_derive(next_attr, &next_derive_lists) {
+                        merge_derive_list(&mut derive_lists[0], &mut next_derive_lists[0]);
+                        merged.push(removal_range(&text, next_range));
+                    }
+                }
+            }
+
+            if plain_derive && !siblings.contains(&attr_range.start) {
+                // the first `#[derive(...)]` of an item
+                seen_derives.clear();
+                siblings = following_attributes(&text, attr_range.end, &code_map)
+                    .into_iter()
+                    .map(|r| r.start)
+                    .collect();
+            }
+            let item = if options.kind_orders.is_empty() && options.order_command.is_none() {
+                None
+            } else {
+                find_item(&text, attr_range.end, &code_map)
+            };
+            let kind_order = item.and_then(|(kind, _)| options.kind_orders.get(&kind));
+            let (custom_order, unlisted) = match kind_order {
+                Some(kind_order) => (&kind_order.custom_order, kind_order.unlisted),
+                None => (&options.custom_order, options.unlisted),
+            };
+
+            let line = line_index(attr_range.start) + 1;
+            let mut removed_all = false;
+            for derive_list in &mut derive_lists {
+                // duplicates across attributes are only checked for unconditional derives
+                let mut seen_in_list = HashSet::new();
+                let seen = if plain_derive {
+                    &mut seen_derives
+                } else {
+                    &mut seen_in_list
+                };
+                let duplicates =
+                    find_duplicate_derives(&derive_list.derives, seen, &imports, &options.aliases);
+                for i in &duplicates {
+                    warnings.push(Warning {
+                        line,
+                        message: format!("duplicate derive `{}`", derive_list.derives[*i].s),
+                        fixed: options.dedupe,
+                    });
+                }
+                if options.dedupe && !duplicates.is_empty() {
+                    for i in duplicates.iter().rev() {
+                        derive_list.derives.remove(*i);
+                    }
+                    removed_all = derive_list.derives.is_empty();
+                }
+
+                derive_list.derives = match &options.order_command {
+                    Some(order_command) => {
+                        let request = OrderRequest {
+                            file: file_name,
+                            item: item.map(|(_, name)| name),
+                            kind: item.map(|(kind, _)| kind),
+                            derives: derive_list.derives.iter().map(DeriveTrait::path).collect(),
+                        };
+                        let permutation = order_command
+                            .order(&request)
+                            .map_err(|e| std::io::Error::other(format!("line {line}: {e}")))?;
+                        permutation
+                            .into_iter()
+                            .map(|i| derive_list.derives[i].clone())
+                            .collect()
+                    }
+                    // pairs and constraints adjust the configured order, while the order of the command is final
+                    None => {
+                        let mut derives = sort_derive_traits(
+                            &derive_list.derives,
+                            custom_order,
+                            unlisted,
+                            &imports,
+                            options,
+                        );
+                        if !options.pairs.is_empty() {
+                            derives = pair_derive_traits(
+                                &derives,
+                                &options.pairs,
+                                &imports,
+                                &options.aliases,
+                            );
+                        }
+                        if let Some(constraints) = &options.constraints {
+                            derives = constraints.apply(&derives, &imports, &options.aliases);
+                        }
+                        derives
+                    }
+                };
+            }
+            if removed_all && plain_derive {
+                let removed = removal_range(&text, attr_range);
+                new_text.push_str(&text[copied..removed.start]);
+                copied = removed.end;
+                continue;
+            }
+
+            let indent = standalone_indent(&text, attr_range.clone());
+            let attrs_derive_lists = match &options.split {
+                Some(groups) if plain_derive => {
+                    split_derive_list(&derive_lists[0], groups, &imports, &options.aliases)
+                        .into_iter()
+                        .map(|derive_list| vec![derive_list])
+                        .collect()
+                }
+                _ => vec![derive_lists],
+            };
+            let new_attrs: Vec<String> = attrs_derive_lists
+                .into_iter()
+                .map(|mut derive_lists| {
+                    if let (Some(reflow), Some(indent)) = (&options.reflow, indent) {
+                        if plain_derive {
+                            reflow_derive_list(&mut derive_lists[0], indent, reflow, options);
+                        } else if let Some(new_attr) =
+                            reflow_cfg_attr(attr, &derive_lists, indent, reflow)
+                        {
+                            return new_attr;
+                        }
+                    }
+                    replace_line(attr, &derive_lists, options)
+                })
+                .collect();
+            // split attributes are written on separate lines if the original one stands alone
+            let separator = indent.map_or(" ".to_string(), |indent| format!("\n{indent}"));
+            new_text.push_str(&text[copied..attr_range.start]);
+            new_text.push_str(&new_attrs.join(&separator));
+            copied = attr_range.end;
+        }
+    }
+    new_text.push_str(&text[copied..]);
+
+    warnings.sort_by_key(|w| w.line);
+
+    let new_lines = split_lines(&new_text).collect();
+    Ok((old_lines, new_lines, warnings))
+}
+
+/// Returns whether each line should be sorted, considering the lines found by grep and the disable comments.
+fn sortable_lines(lines: &[String], line_numbers: Option<&HashSet<usize>>) -> Vec<bool> {
+    let mut sortable_lines = Vec::with_capacity(lines.len());
 
     let mut disable_next_line = false;
     let mut disable_range = false;
 
-    for (i, line) in reader.lines_with_terminator().enumerate() {
+    for (i, line) in lines.iter().enumerate() {
         let n = i + 1;
-        let line = line?;
-
         let should_sort = line_numbers.is_none_or(|line_numbers| line_numbers.contains(&n));
-        let new_line = if !disable_next_line && !disable_range && should_sort {
-            if let Some(derives) = parse_derive_traits(&line) {
-                let sorted_derives = sort_derive_traits(&derives, custom_order, preserve);
-                replace_line(&line, &sorted_derives)
-            } else {
-                line.clone()
-            }
-        } else {
-            line.clone()
-        };
+        sortable_lines.push(!disable_next_line && !disable_range && should_sort);
 
         disable_next_line = false;
         if line.contains(DISABLE_NEXT_LINE) {
@@ -79,106 +516,1298 @@ fn sort_reader<R: BufRead>(
         if line.contains(DISABLE_END) {
             disable_range = false;
         }
+    }
+
+    sortable_lines
+}
+
+/// Finds all attributes that start in `line` and returns their ranges in `text`.
+/// An attribute may span multiple lines. Attributes in comments or string literals are ignored.
+fn find_attributes(text: &str, line: Range<usize>, code_map: &CodeMap) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for m in ATTR_START_RE.find_iter(&text[line.clone()]) {
+        let start = line.start + m.start();
+        if !code_map.is_code(start) || ranges.last().is_some_and(|r| start < r.end) {
+            continue;
+        }
+        match attribute_end(text, start, code_map) {
+            Some(end) => ranges.push(start..end),
+            None => break,
+        }
+    }
+    ranges
+}
+
+fn attribute_end(text: &str, start: usize, code_map: &CodeMap) -> Option<usize> {
+    let mut depth = 0;
+    for (i, b) in text[start..].bytes().enumerate() {
+        if !code_map.is_code(start + i) {
+            continue;
+        }
+        match b {
+            b'[' => depth += 1,
+            b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(start + i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
 
-        old_lines.push(line);
-        new_lines.push(new_line);
+/// Returns the attributes that follow the attribute ending at `end` on the same item.
+fn following_attributes(text: &str, end: usize, code_map: &CodeMap) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut pos = end;
+    loop {
+        pos = skip_blank(text, pos, code_map);
+        if !text[pos..].starts_with("#[") {
+            break;
+        }
+        let Some(attr_end) = attribute_end(text, pos, code_map) else {
+            break;
+        };
+        ranges.push(pos..attr_end);
+        pos = attr_end;
     }
+    ranges
+}
 
-    Ok((old_lines, new_lines))
+/// Skips whitespace and comments, including doc comments.
+fn skip_blank(text: &str, mut pos: usize, code_map: &CodeMap) -> usize {
+    while pos < text.len() && (!code_map.is_code(pos) || text.as_bytes()[pos].is_ascii_whitespace())
+    {
+        pos += 1;
+    }
+    pos
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Returns the kind and the name of the item that the attribute ending at `end` belongs to.
+fn find_item<'a>(text: &'a str, end: usize, code_map: &CodeMap) -> Option<(ItemKind, &'a str)> {
+    let mut pos = following_attributes(text, end, code_map)
+        .last()
+        .map_or(end, |r| r.end);
+    let word_at = |pos: usize| {
+        let pos = skip_blank(text, pos, code_map);
+        let word_len = text[pos..]
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(text.len() - pos);
+        (&text[pos..pos + word_len], pos + word_len)
+    };
+    loop {
+        let (word, word_end) = word_at(pos);
+        pos = word_end;
+        let kind = match word {
+            "struct" => ItemKind::Struct,
+            "enum" => ItemKind::Enum,
+            "union" => ItemKind::Union,
+            "pub" => {
+                // `pub(crate)`, `pub(in path)`, ...
+                let next = skip_blank(text, pos, code_map);
+                if text[next..].starts_with('(') {
+                    pos = next + text[next..].find(')')? + 1;
+                }
+                continue;
+            }
+            _ => return None,
+        };
+        return Some((kind, word_at(pos).0));
+    }
+}
+
+/// Appends the derives of `other` to `derive_list`.
+/// A one-line list becomes one derive per line if a line comment is merged into it.
+fn merge_derive_list(derive_list: &mut DeriveList, other: &mut DeriveList) {
+    derive_list.derives.append(&mut other.derives);
+    if !other.dangling_comment.is_empty() {
+        push_comment(&mut derive_list.dangling_comment, &other.dangling_comment);
+    }
+    let has_line_comment = derive_list
+        .derives
+        .iter()
+        .flat_map(|d| [&d.leading_comment, &d.trailing_comment])
+        .chain([&derive_list.dangling_comment])
+        .any(|c| c.contains("//"));
+    if derive_list.layout == Layout::Horizontal && has_line_comment {
+        derive_list.layout = Layout::Vertical;
+        derive_list.leading = format!("\n{}", other.wrapped_indent());
+        derive_list.trailing = other.trailing.clone();
+        derive_list.trailing_comma = true;
+    }
+}
+
+/// Whether the attribute is a `#[derive(...)]` without `cfg_attr`.
+fn is_plain_derive(attr: &str, derive_lists: &[DeriveList]) -> bool {
+    match derive_lists {
+        [derive_list] => {
+            attr[..derive_list.range.start] == *"#[derive("
+                && attr[derive_list.range.end..] == *")]"
+        }
+        _ => false,
+    }
+}
+
+/// Returns the range to remove for a merged attribute: its whole lines if nothing else is written on them,
+/// otherwise the attribute and the whitespace following it.
+fn removal_range(text: &str, attr_range: Range<usize>) -> Range<usize> {
+    if let Some(indent) = standalone_indent(text, attr_range.clone()) {
+        let line_end = text[attr_range.end..]
+            .find('\n')
+            .map_or(text.len(), |p| attr_range.end + p + 1);
+        attr_range.start - indent.len()..line_end
+    } else {
+        let rest = &text[attr_range.end..];
+        let whitespace = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+        attr_range.start..attr_range.end + whitespace
+    }
+}
+
+/// Returns the indentation of the attribute if nothing else is written on its lines.
+fn standalone_indent(text: &str, attr_range: Range<usize>) -> Option<&str> {
+    let line_start = text[..attr_range.start].rfind('\n').map_or(0, |p| p + 1);
+    let indent = &text[line_start..attr_range.start];
+    let rest = text[attr_range.end..].split('\n').next().unwrap();
+    (indent.trim().is_empty() && rest.trim().is_empty()).then_some(indent)
+}
+
+fn split_lines(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.split_inclusive('\n').map(Into::into)
+}
+
+/// Derives built into the compiler and their paths in `core`.
+const BUILTIN_DERIVES: &[(&str, &str)] = &[
+    ("Clone", "core::clone::Clone"),
+    ("Copy", "core::marker::Copy"),
+    ("Debug", "core::fmt::Debug"),
+    ("Default", "core::default::Default"),
+    ("Eq", "core::cmp::Eq"),
+    ("Hash", "core::hash::Hash"),
+    ("Ord", "core::cmp::Ord"),
+    ("PartialEq", "core::cmp::PartialEq"),
+    ("PartialOrd", "core::cmp::PartialOrd"),
+];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 struct DeriveTrait {
     s: String,
     base_name: String,
+    /// Comments written before the derive, including the whitespace that follows them
+    leading_comment: String,
+    /// Comments written after the derive on the same line
+    trailing_comment: String,
 }
 
-fn parse_derive_traits(line: &str) -> Option<Vec<DeriveTrait>> {
-    let derives_str = if let Some(caps) = DERIVE_RE.captures(line) {
-        caps.get(1).map(|m| m.as_str())
-    } else if let Some(caps) = CFG_ATTR_RE.captures(line) {
-        caps.get(2).map(|m| m.as_str())
-    } else {
-        None
+impl DeriveTrait {
+    /// Returns the derive written with the path it refers to, resolved through the imports of the file
+    /// and then `aliases`, or `None` if it cannot be resolved and `aliases` has no entry for it as written.
+    fn resolve(&self, imports: &Imports, aliases: &HashMap<String, String>) -> Option<DeriveTrait> {
+        let written = self.path();
+        let path = match imports.resolve(&written) {
+            Resolution::Path(path) => aliases.get(&path).cloned().unwrap_or(path),
+            Resolution::Unresolved => aliases.get(&written).cloned()?,
+        };
+        Some(DeriveTrait {
+            base_name: path.rsplit("::").next().unwrap_or(&path).into(),
+            s: path,
+            ..Default::default()
+        })
+    }
+
+    /// The first segment of the path, or an empty string if the derive has no path.
+    fn crate_name(&self) -> String {
+        let path = self.path();
+        match path.split_once("::") {
+            Some((crate_name, _)) => crate_name.to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// The path as written, without whitespace and a leading `::`.
+    fn path(&self) -> String {
+        normalize_path(&self.s)
+    }
+
+    /// The path that the derive refers to, so that different spellings of the same derive are equal.
+    /// Built-in derives are resolved to their paths in `core`.
+    fn resolved_path(&self) -> String {
+        let path = self.path();
+        let path = path.as_str();
+        if let Some(rest) = path.strip_prefix("std::") {
+            return format!("core::{rest}");
+        }
+        BUILTIN_DERIVES
+            .iter()
+            .find(|(name, _)| *name == path)
+            .map_or_else(|| path.to_string(), |(_, core_path)| core_path.to_string())
+    }
+}
+
+/// An entry in the custom order that stands for a slot holding all the derives matching it.
+#[derive(Debug)]
+pub struct OrderPattern {
+    regex: Regex,
+    /// Whether the pattern is matched against the full path instead of the base name
+    full_path: bool,
+}
+
+impl OrderPattern {
+    /// Parses a glob (`strum::*`, `*Eq`) or regex (`re:^Partial`) entry, or returns `None` for a plain entry.
+    pub fn parse(entry: &str) -> Result<Option<OrderPattern>, regex::Error> {
+        let source = if let Some(re) = entry.strip_prefix("re:") {
+            re.to_string()
+        } else if entry.contains(['*', '?']) {
+            glob_to_regex(&normalize_path(entry))
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(OrderPattern {
+            full_path: source.contains("::"),
+            regex: Regex::new(&source)?,
+        }))
+    }
+
+    fn is_match(&self, derive: &DeriveTrait) -> bool {
+        if self.full_path {
+            self.regex.is_match(&derive.path())
+        } else {
+            self.regex.is_match(&derive.base_name)
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Removes whitespace and a leading `::` from a path.
+pub fn normalize_path(s: &str) -> String {
+    let path: String = s.split_whitespace().collect();
+    path.trim_start_matches("::").to_string()
+}
+
+/// A `derive(...)` found in an attribute.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct DeriveList {
+    /// The range of the contents between the parentheses in the attribute
+    range: Range<usize>,
+    derives: Vec<DeriveTrait>,
+    layout: Layout,
+    /// Whitespace after the opening parenthesis
+    leading: String,
+    /// Whitespace before the closing parenthesis
+    trailing: String,
+    trailing_comma: bool,
+    /// Whitespace before and after each comma
+    separators: Vec<(String, String)>,
+    /// For a wrapped list, the indentation of the new line at each position between derives that has a line break
+    line_breaks: Vec<Option<String>>,
+    /// Comments after the last derive that do not belong to it (e.g. a commented out derive)
+    dangling_comment: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// All derives in one line
+    #[default]
+    Horizontal,
+    /// One derive per line, each line starts with the leading whitespace of the list
+    Vertical,
+    /// Derives on multiple lines as written otherwise, e.g. rustfmt's `#[derive(\n    A, B,\n)]`.
+    /// The line breaks are kept at the same positions between derives.
+    Wrapped,
+}
+
+impl DeriveList {
+    /// The indentation of the lines in a wrapped list.
+    /// If no derive starts a line, it is one level deeper than the closing parenthesis.
+    fn wrapped_indent(&self) -> String {
+        let indent = self
+            .leading
+            .rsplit_once('\n')
+            .map(|(_, indent)| indent)
+            .or_else(|| self.line_breaks.iter().flatten().next().map(String::as_str));
+        if let Some(indent) = indent {
+            return indent.into();
+        }
+        let closing = self
+            .trailing
+            .rsplit_once('\n')
+            .map_or("", |(_, indent)| indent);
+        let indent_unit = if closing.contains('\t') { "\t" } else { "    " };
+        format!("{closing}{indent_unit}")
+    }
+}
+
+/// Parses an attribute (`#[...]`) and returns the derive lists written in it.
+/// `derive` can be written directly or inside `cfg_attr`, which may be nested.
+/// Lists with macro metavariables or repetitions (e.g. `$($d),*` in `macro_rules!`) are skipped.
+fn parse_derive_lists(attr: &str) -> Vec<DeriveList> {
+    let mut ranges = Vec::new();
+    if attr.starts_with("#[") && attr.ends_with(']') {
+        let code_map = CodeMap::new(attr);
+        collect_derive_list_ranges(attr, 2..attr.len() - 1, &code_map, &mut ranges);
+    }
+
+    ranges
+        .into_iter()
+        .filter(|range| !attr[range.clone()].contains('$'))
+        .map(|range| parse_derive_list(attr, range))
+        .collect()
+}
+
+fn collect_derive_list_ranges(
+    attr: &str,
+    meta: Range<usize>,
+    code_map: &CodeMap,
+    ranges: &mut Vec<Range<usize>>,
+) {
+    let Some((name, args)) = split_meta(attr, meta, code_map) else {
+        return;
     };
 
-    derives_str.map(|s| {
-        s.split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| {
-                let base_name = s.split(':').next_back().unwrap_or(s);
-                DeriveTrait {
-                    s: s.into(),
-                    base_name: base_name.into(),
+    match name {
+        "derive" => ranges.push(args),
+        "cfg_attr" => {
+            // the first argument is the condition, and the rest are the attributes
+            for arg in split_args(attr, args, code_map).into_iter().skip(1) {
+                collect_derive_list_ranges(attr, arg, code_map, ranges);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Splits a meta item like `name(args)` into `name` and the range of `args`.
+fn split_meta<'a>(
+    attr: &'a str,
+    meta: Range<usize>,
+    code_map: &CodeMap,
+) -> Option<(&'a str, Range<usize>)> {
+    let open = meta.start + attr[meta.clone()].find('(')?;
+    let close = meta.start + attr[meta.clone()].trim_end().len() - 1;
+    if attr.as_bytes()[close] != b')' || matching_paren(attr, open, code_map)? != close {
+        return None;
+    }
+    Some((attr[meta.start..open].trim(), open + 1..close))
+}
+
+fn matching_paren(attr: &str, open: usize, code_map: &CodeMap) -> Option<usize> {
+    let mut depth = 0;
+    for (i, b) in attr.bytes().enumerate().skip(open) {
+        if !code_map.is_code(i) {
+            continue;
+        }
+        match b {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
                 }
-            })
-            .collect()
-    })
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits comma separated arguments, ignoring commas in nested parentheses and string literals.
+fn split_args(attr: &str, args: Range<usize>, code_map: &CodeMap) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut depth = 0;
+    let mut start = args.start;
+    for i in args.clone() {
+        if !code_map.is_code(i) {
+            continue;
+        }
+        match attr.as_bytes()[i] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            b',' if depth == 0 => {
+                ranges.push(start..i);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    ranges.push(start..args.end);
+    ranges.retain(|r| !attr[r.clone()].trim().is_empty());
+    ranges
+}
+
+fn parse_derive_list(attr: &str, range: Range<usize>) -> DeriveList {
+    let list = &attr[range.clone()];
+    let bytes = list.as_bytes();
+    let code_map = CodeMap::new(list);
+
+    let mut derives: Vec<DeriveTrait> = Vec::new();
+    // the derive being read and the offset where its path starts
+    let mut current: Option<(DeriveTrait, usize)> = None;
+    let mut leading_comment = String::new();
+    let mut trailing_comma = false;
+    let mut after_comma_in_line = false;
+    let mut separators: Vec<(String, String)> = Vec::new();
+    // whether the whitespace after the last separator is being read
+    let mut after_comma = false;
+    // whitespace since the last comma, comment or path
+    let mut whitespace = String::new();
+    // where each derive starts, including its leading comment, and where its path ends
+    let mut starts: Vec<usize> = Vec::new();
+    let mut ends: Vec<usize> = Vec::new();
+    let mut leading_comment_start: Option<usize> = None;
+    let mut path_end = 0;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if !code_map.is_code(i) {
+            let start = i;
+            while i < bytes.len() && !code_map.is_code(i) {
+                i += 1;
+            }
+            let comment = &list[start..i];
+            if after_comma {
+                separators.last_mut().unwrap().1 = std::mem::take(&mut whitespace);
+                after_comma = false;
+            }
+            whitespace.clear();
+            let ends_line = list[i..].split('\n').next().unwrap().trim().is_empty();
+            if let Some((derive, _)) = current.as_mut() {
+                push_comment(&mut derive.trailing_comment, comment);
+            } else if after_comma_in_line && ends_line && !derives.is_empty() {
+                push_comment(&mut derives.last_mut().unwrap().trailing_comment, comment);
+            } else {
+                if leading_comment.is_empty() {
+                    leading_comment_start = Some(start);
+                }
+                leading_comment.push_str(comment);
+            }
+            continue;
+        }
+
+        match bytes[i] {
+            b',' => {
+                if let Some((derive, _)) = current.take() {
+                    derives.push(derive);
+                    ends.push(path_end);
+                }
+                if !derives.is_empty() {
+                    separators.push((std::mem::take(&mut whitespace), String::new()));
+                    after_comma = true;
+                }
+                whitespace.clear();
+                trailing_comma = true;
+                after_comma_in_line = true;
+                i += 1;
+            }
+            b if b.is_ascii_whitespace() => {
+                if b == b'\n' {
+                    after_comma_in_line = false;
+                }
+                if !leading_comment.is_empty() {
+                    leading_comment.push(b as char);
+                }
+                whitespace.push(b as char);
+                i += 1;
+            }
+            _ => {
+                let mut end = i;
+                while end < bytes.len()
+                    && code_map.is_code(end)
+                    && bytes[end] != b','
+                    && !bytes[end].is_ascii_whitespace()
+                {
+                    end += 1;
+                }
+                if after_comma {
+                    separators.last_mut().unwrap().1 = std::mem::take(&mut whitespace);
+                    after_comma = false;
+                }
+                whitespace.clear();
+                let start = current.as_ref().map_or(i, |(_, start)| *start);
+                let s = &list[start..end];
+                let base_name = s.split(':').next_back().unwrap_or(s).trim();
+                let derive = match current.take() {
+                    Some((derive, _)) => DeriveTrait {
+                        s: s.into(),
+                        base_name: base_name.into(),
+                        ..derive
+                    },
+                    None => {
+                        starts.push(leading_comment_start.take().unwrap_or(start));
+                        DeriveTrait {
+                            s: s.into(),
+                            base_name: base_name.into(),
+                            leading_comment: std::mem::take(&mut leading_comment),
+                            ..Default::default()
+                        }
+                    }
+                };
+                current = Some((derive, start));
+                path_end = end;
+                trailing_comma = false;
+                i = end;
+            }
+        }
+    }
+    if let Some((derive, _)) = current {
+        derives.push(derive);
+        ends.push(path_end);
+    }
+
+    let line_breaks: Vec<Option<String>> = (1..derives.len())
+        .map(|i| line_break(&list[ends[i - 1]..starts[i]]))
+        .collect();
+    let starts_on_new_line = starts
+        .first()
+        .is_some_and(|start| list[..*start].contains('\n'));
+    let layout = if !list.contains('\n') {
+        Layout::Horizontal
+    } else if derives.is_empty() || (starts_on_new_line && line_breaks.iter().all(Option::is_some))
+    {
+        Layout::Vertical
+    } else {
+        Layout::Wrapped
+    };
+
+    DeriveList {
+        range,
+        layout,
+        line_breaks: if layout == Layout::Wrapped {
+            line_breaks
+        } else {
+            Vec::new()
+        },
+        leading: list[..list.len() - list.trim_start().len()].into(),
+        trailing: list[list.trim_end().len()..].into(),
+        trailing_comma: trailing_comma && !derives.is_empty(),
+        derives,
+        separators,
+        dangling_comment: leading_comment.trim_end().into(),
+    }
+}
+
+/// Returns the indentation of the next line if `gap` ends with a line break followed by whitespace.
+fn line_break(gap: &str) -> Option<String> {
+    let (_, indent) = gap.rsplit_once('\n')?;
+    indent.trim().is_empty().then(|| indent.into())
+}
+
+fn push_comment(comments: &mut String, comment: &str) {
+    if !comments.is_empty() {
+        comments.push(' ');
+    }
+    comments.push_str(comment);
+}
+
+/// Returns the indices of the derives whose resolved path is in `seen` or appears earlier in `derives`,
+/// and adds the resolved paths to `seen`.
+fn find_duplicate_derives(
+    derives: &[DeriveTrait],
+    seen: &mut HashSet<String>,
+    imports: &Imports,
+    aliases: &HashMap<String, String>,
+) -> Vec<usize> {
+    derives
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| {
+            // an unresolved derive is compared as written
+            let resolved = d.resolve(imports, aliases).unwrap_or_else(|| (*d).clone());
+            !seen.insert(resolved.resolved_path())
+        })
+        .map(|(i, _)| i)
+        .collect()
 }
 
 fn sort_derive_traits(
     derives: &[DeriveTrait],
     custom_order: &Option<Vec<String>>,
-    preserve: bool,
+    unlisted: Unlisted,
+    imports: &Imports,
+    options: &SortOptions,
 ) -> Vec<DeriveTrait> {
-    const IGNORE: &usize = &10_000; // large enough
+    const IGNORE: usize = 10_000; // large enough
 
-    let order_map: HashMap<String, usize> = match custom_order {
-        Some(custom_order) => {
-            let head_order = custom_order
-                .iter()
-                .take_while(|s| *s != "...")
-                .enumerate()
-                .map(|(i, s)| (s.clone(), i));
-            let tail_order = custom_order
-                .iter()
-                .skip_while(|s| *s != "...")
-                .skip(1)
-                .enumerate()
-                .map(|(i, s)| (s.clone(), i + IGNORE + 1));
-            head_order.chain(tail_order).collect()
+    // (position in the order, position in the group)
+    let mut order_map: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut patterns: Vec<(OrderPattern, (usize, usize))> = Vec::new();
+    let mut group_orders: HashMap<usize, GroupOrder> = HashMap::new();
+    if let Some(custom_order) = custom_order {
+        let ellipsis = custom_order.iter().position(|s| s == "...");
+        let mut group_members = Vec::new();
+        for (i, s) in custom_order.iter().enumerate() {
+            let priority = match ellipsis {
+                Some(e) if i == e => continue,
+                Some(e) if i > e => i - e + IGNORE,
+                _ => i,
+            };
+            if let Some(name) = s.strip_prefix('@') {
+                // unknown groups are reported when reading the order
+                if let Some(group) = options.groups.get(name) {
+                    group_orders.insert(priority, group.order);
+                    for (j, member) in group.derives.iter().enumerate() {
+                        let inner = match group.order {
+                            GroupOrder::Listed => j,
+                            GroupOrder::Alphabetical | GroupOrder::Preserve => 0,
+                        };
+                        group_members.push((member, (priority, inner)));
+                    }
+                }
+                continue;
+            }
+            match OrderPattern::parse(s) {
+                Ok(Some(pattern)) => patterns.push((pattern, (priority, 0))),
+                Ok(None) => {
+                    order_map.insert(normalize_path(s), (priority, 0));
+                }
+                // invalid patterns are reported when reading the order
+                Err(_) => {}
+            }
+        }
+        // entries written directly in the order take precedence over the members of groups
+        for (member, priority) in group_members {
+            match OrderPattern::parse(member) {
+                Ok(Some(pattern)) => patterns.push((pattern, priority)),
+                Ok(None) => {
+                    order_map.entry(normalize_path(member)).or_insert(priority);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    // entries with `::` match the full path, and take precedence over the ones matching the base name.
+    // the path as written is tried before the resolved one, so that `Debug` and `std::fmt::Debug` can be
+    // told apart. patterns are only tried if no entry matches.
+    let priority = |derive: &DeriveTrait, written: &DeriveTrait| {
+        order_map
+            .get(&written.path())
+            .or_else(|| order_map.get(&derive.path()))
+            .or_else(|| order_map.get(&derive.base_name))
+            .copied()
+            .or_else(|| {
+                patterns
+                    .iter()
+                    .find(|(pattern, _)| pattern.is_match(derive))
+                    .map(|(_, priority)| *priority)
+            })
+            .unwrap_or((IGNORE, 0))
+    };
+    // whether derives with the same priority keep the original order instead of being sorted alphabetically
+    let keeps_order = |priority: (usize, usize)| match group_orders.get(&priority.0) {
+        Some(GroupOrder::Preserve) => true,
+        Some(GroupOrder::Alphabetical) => false,
+        Some(GroupOrder::Listed) | None => {
+            unlisted == Unlisted::Preserve
+                && (priority.0 == IGNORE || patterns.iter().any(|(_, p)| *p == priority))
         }
-        None => HashMap::new(),
+    };
+    let sort_mode = options.sort_mode;
+    let alphabetical = |a: &DeriveTrait, b: &DeriveTrait| {
+        sort_mode
+            .compare(&a.base_name, &b.base_name)
+            .then_with(|| sort_mode.compare(&a.s, &b.s))
     };
 
-    let mut sorted_derives = derives.to_vec();
-    sorted_derives.sort_by(|a, b| {
-        let priority_a = order_map.get(&a.base_name).unwrap_or(IGNORE);
-        let priority_b = order_map.get(&b.base_name).unwrap_or(IGNORE);
+    // derives are compared by the paths they refer to, and written as they are
+    let mut resolved_derives: Vec<(Option<DeriveTrait>, &DeriveTrait)> = derives
+        .iter()
+        .map(|derive| (derive.resolve(imports, &options.aliases), derive))
+        .collect();
+    resolved_derives.sort_by(|(resolved_a, written_a), (resolved_b, written_b)| {
+        // an unresolved derive is matched as written
+        let a = resolved_a.as_ref().unwrap_or(written_a);
+        let b = resolved_b.as_ref().unwrap_or(written_b);
+        let priority_a = priority(a, written_a);
+        let priority_b = priority(b, written_b);
 
-        if preserve && priority_a == IGNORE && priority_b == IGNORE {
+        if priority_a != priority_b {
+            priority_a.cmp(&priority_b)
+        } else if keeps_order(priority_a) {
             std::cmp::Ordering::Equal
+        } else if priority_a.0 != IGNORE {
+            alphabetical(written_a, written_b)
         } else {
-            priority_a
-                .cmp(priority_b)
-                .then_with(|| a.base_name.cmp(&b.base_name))
-                .then_with(|| a.s.cmp(&b.s))
+            match unlisted {
+                // unresolved derives come after all the crates
+                Unlisted::ByCrate => match (resolved_a, resolved_b) {
+                    (Some(a), Some(b)) => sort_mode.compare(&a.crate_name(), &b.crate_name()),
+                    (a, b) => a.is_none().cmp(&b.is_none()),
+                }
+                .then_with(|| alphabetical(written_a, written_b)),
+                Unlisted::ByFrequency => {
+                    let frequency =
+                        |d: &DeriveTrait| options.frequencies.get(&d.base_name).copied();
+                    frequency(written_b)
+                        .cmp(&frequency(written_a))
+                        .then_with(|| alphabetical(written_a, written_b))
+                }
+                Unlisted::Alphabetical | Unlisted::Preserve => alphabetical(written_a, written_b),
+            }
         }
     });
 
-    sorted_derives
+    resolved_derives
+        .into_iter()
+        .map(|(_, derive)| derive.clone())
+        .collect()
 }
 
-fn replace_line(line: &str, sorted_derives: &[DeriveTrait]) -> String {
-    let sorted_derive_str = sorted_derives
+/// Moves the first derive of each pair right before the second one, so that each pair is sorted as one unit
+/// at the position of the second one.
+fn pair_derive_traits(
+    derives: &[DeriveTrait],
+    pairs: &[(String, String)],
+    imports: &Imports,
+    aliases: &HashMap<String, String>,
+) -> Vec<DeriveTrait> {
+    let matches_entry =
+        |derive: &DeriveTrait, entry: &str| matches_entry(derive, entry, imports, aliases);
+    let mut paired_derives = derives.to_vec();
+    for (first, second) in pairs {
+        let first = normalize_path(first);
+        let second = normalize_path(second);
+        let Some(i) = paired_derives.iter().position(|d| matches_entry(d, &first)) else {
+            continue;
+        };
+        if !paired_derives.iter().any(|d| matches_entry(d, &second)) {
+            continue;
+        }
+        let derive = paired_derives.remove(i);
+        let j = paired_derives
+            .iter()
+            .position(|d| matches_entry(d, &second))
+            .unwrap();
+        paired_derives.insert(j, derive);
+    }
+    paired_derives
+}
+
+/// Rules that some derives come before others, e.g. `PartialEq < Eq`.
+#[derive(Debug, Default)]
+pub struct Constraints {
+    /// Derive names or paths that appear in the rules
+    entries: Vec<String>,
+    /// `before[i][j]` is whether `entries[i]` must come before `entries[j]`, directly or through other rules
+    before: Vec<Vec<bool>>,
+}
+
+impl Constraints {
+    /// Parses rules written as `A < B` (or `A < B < C`), and returns an error if they contradict each other.
+    pub fn new(rules: &[String]) -> Result<Constraints, String> {
+        let mut entries: Vec<String> = Vec::new();
+        let mut edges = Vec::new();
+        for rule in rules {
+            let names: Vec<String> = rule.split('<').map(normalize_path).collect();
+            if names.len() < 2 || names.iter().any(|name| name.is_empty()) {
+                return Err(format!("Invalid constraint '{rule}': expected 'A < B'"));
+            }
+            let ids: Vec<usize> = names
+                .into_iter()
+                .map(|name| match entries.iter().position(|e| *e == name) {
+                    Some(i) => i,
+                    None => {
+                        entries.push(name);
+                        entries.len() - 1
+                    }
+                })
+                .collect();
+            edges.extend(ids.windows(2).map(|w| (w[0], w[1])));
+        }
+
+        let n = entries.len();
+        let mut before = vec![vec![false; n]; n];
+        for &(a, b) in &edges {
+            before[a][b] = true;
+        }
+        // transitive closure
+        for k in 0..n {
+            let after_k = before[k].clone();
+            for row in before.iter_mut().filter(|row| row[k]) {
+                for (cell, after) in row.iter_mut().zip(&after_k) {
+                    *cell |= after;
+                }
+            }
+        }
+
+        if let Some(i) = (0..n).find(|&i| before[i][i]) {
+            let cycle: Vec<&str> = find_cycle(&edges, i)
+                .into_iter()
+                .map(|j| entries[j].as_str())
+                .collect();
+            return Err(format!("Contradictory constraints: {}", cycle.join(" < ")));
+        }
+
+        Ok(Constraints { entries, before })
+    }
+
+    /// Reorders the derives to satisfy the rules, keeping the given order as much as possible.
+    fn apply(
+        &self,
+        derives: &[DeriveTrait],
+        imports: &Imports,
+        aliases: &HashMap<String, String>,
+    ) -> Vec<DeriveTrait> {
+        let matches: Vec<Vec<usize>> = derives
+            .iter()
+            .map(|derive| {
+                (0..self.entries.len())
+                    .filter(|&i| matches_entry(derive, &self.entries[i], imports, aliases))
+                    .collect()
+            })
+            .collect();
+        let must_precede = |a: usize, b: usize| {
+            matches[a]
+                .iter()
+                .any(|&i| matches[b].iter().any(|&j| self.before[i][j]))
+        };
+
+        // place the derives one by one, each right before the first placed derive it must precede,
+        // so that derives not involved in a violated rule keep their order
+        let mut order: Vec<usize> = Vec::with_capacity(derives.len());
+        for b in 0..derives.len() {
+            let position = order
+                .iter()
+                .position(|&a| must_precede(b, a))
+                .unwrap_or(order.len());
+            order.insert(position, b);
+        }
+        order.into_iter().map(|i| derives[i].clone()).collect()
+    }
+}
+
+/// Returns the shortest path of the edges from `start` back to itself, e.g. `[start, a, b, start]`.
+fn find_cycle(edges: &[(usize, usize)], start: usize) -> Vec<usize> {
+    let mut parents: HashMap<usize, usize> = HashMap::new();
+    let mut queue = std::collections::VecDeque::from([start]);
+    while let Some(current) = queue.pop_front() {
+        for &(_, next) in edges.iter().filter(|(from, _)| *from == current) {
+            if parents.contains_key(&next) {
+                continue;
+            }
+            parents.insert(next, current);
+            if next == start {
+                let mut path = vec![start];
+                let mut node = current;
+                while node != start {
+                    path.push(node);
+                    node = parents[&node];
+                }
+                path.push(start);
+                path.reverse();
+                return path;
+            }
+            queue.push_back(next);
+        }
+    }
+    vec![start]
+}
+
+/// Whether the derive is referred to by the entry, which is a full path if it contains `::`, or a base name otherwise.
+/// The derive matches either as written or as the path it resolves to through the imports and `aliases`.
+fn matches_entry(
+    derive: &DeriveTrait,
+    entry: &str,
+    imports: &Imports,
+    aliases: &HashMap<String, String>,
+) -> bool {
+    let matches = |derive: &DeriveTrait| {
+        if entry.contains("::") {
+            derive.path() == entry
+        } else {
+            derive.base_name == entry
+        }
+    };
+    matches(derive)
+        || derive
+            .resolve(imports, aliases)
+            .is_some_and(|d| matches(&d))
+}
+
+/// Splits the derives into one list per group, in the order of the groups.
+/// A derive belongs to the first group that contains its path or its base name.
+/// Derives that do not belong to any group are put in the last list.
+fn split_derive_list(
+    derive_list: &DeriveList,
+    groups: &[Vec<String>],
+    imports: &Imports,
+    aliases: &HashMap<String, String>,
+) -> Vec<DeriveList> {
+    let mut grouped_derives = vec![Vec::new(); groups.len() + 1];
+    for derive in &derive_list.derives {
+        let i = groups
+            .iter()
+            .position(|group| {
+                group
+                    .iter()
+                    .any(|g| matches_entry(derive, &normalize_path(g), imports, aliases))
+            })
+            .unwrap_or(groups.len());
+        grouped_derives[i].push(derive.clone());
+    }
+    grouped_derives.retain(|derives| !derives.is_empty());
+
+    let n = grouped_derives.len();
+    grouped_derives
+        .into_iter()
+        .enumerate()
+        .map(|(i, derives)| DeriveList {
+            derives,
+            dangling_comment: if i + 1 == n {
+                derive_list.dangling_comment.clone()
+            } else {
+                String::new()
+            },
+            ..derive_list.clone()
+        })
+        .collect()
+}
+
+/// Chooses the layout of a standalone `#[derive(...)]` as rustfmt would:
+/// one line if it fits in `max_width`, otherwise the derives on a single line of their own
+/// (`#[derive(\n    A, B,\n)]`) if they fit there, otherwise one derive per line.
+fn reflow_derive_list(
+    derive_list: &mut DeriveList,
+    indent: &str,
+    reflow: &Reflow,
+    options: &SortOptions,
+) {
+    let rustfmt = &reflow.rustfmt;
+    let has_line_comment = derive_list
+        .derives
+        .iter()
+        .flat_map(|d| [&d.leading_comment, &d.trailing_comment])
+        .chain([&derive_list.dangling_comment])
+        .any(|c| c.contains("//"));
+    let exceeds_threshold = reflow
+        .vertical_threshold
+        .is_some_and(|n| derive_list.derives.len() > n);
+
+    let horizontal = DeriveList {
+        layout: Layout::Horizontal,
+        leading: String::new(),
+        trailing: String::new(),
+        separators: match derive_list.layout {
+            Layout::Horizontal => derive_list.separators.clone(),
+            // the separators of a multiline list may contain line breaks
+            Layout::Vertical | Layout::Wrapped => Vec::new(),
+        },
+        line_breaks: Vec::new(),
+        ..derive_list.clone()
+    };
+    let items = format_derive_list(&horizontal, options);
+    let indent_width = rustfmt.width(indent);
+    // rustfmt leaves room for 4 more columns when it puts an attribute on one line
+    let fits_in_line =
+        indent_width + rustfmt.width(&format!("#[derive({items})]")) + 4 <= rustfmt.max_width;
+    // the trailing comma of the nested derives is not counted
+    let fits_in_nested_line = indent_width
+        + rustfmt.width(&rustfmt.indent_unit())
+        + rustfmt.width(items.trim_end().trim_end_matches(',').trim_end())
+        <= rustfmt.max_width;
+
+    let indent_unit = rustfmt.indent_unit();
+    if has_line_comment || exceeds_threshold {
+        derive_list.layout = Layout::Vertical;
+    } else if fits_in_line {
+        *derive_list = horizontal;
+        return;
+    } else if fits_in_nested_line {
+        *derive_list = DeriveList {
+            layout: Layout::Wrapped,
+            ..horizontal
+        };
+    } else {
+        derive_list.layout = Layout::Vertical;
+    }
+    derive_list.leading = format!("\n{indent}{indent_unit}");
+    derive_list.trailing = format!("\n{indent}");
+    // rustfmt puts a trailing comma in multiline lists
+    derive_list.trailing_comma = true;
+}
+
+/// Rewrites a standalone `#[cfg_attr(predicate, derive(...))]` in the layout rustfmt would choose for it.
+/// Returns `None` for any other `cfg_attr`, or if it contains comments or a predicate other than a single
+/// name or `name = "value"`.
+fn reflow_cfg_attr(
+    attr: &str,
+    derive_lists: &[DeriveList],
+    indent: &str,
+    reflow: &Reflow,
+) -> Option<String> {
+    const CFG_ATTR: &str = "#[cfg_attr(";
+    const DERIVE: &str = "derive(";
+    // rustfmt's `short_array_element_width_threshold`
+    const SHORT_ITEM_WIDTH: usize = 10;
+
+    let [derive_list] = derive_lists else {
+        return None;
+    };
+    if !attr.starts_with(CFG_ATTR)
+        || !attr.ends_with(")]")
+        || attr.contains("//")
+        || attr.contains("/*")
+    {
+        return None;
+    }
+    let code_map = CodeMap::new(attr);
+    let args = split_args(attr, CFG_ATTR.len()..attr.len() - ")]".len(), &code_map);
+    let [predicate, derive] = args.as_slice() else {
+        return None;
+    };
+    let predicate = attr[predicate.clone()].trim();
+    let derive_start = derive.start + attr[derive.clone()].find(|c: char| !c.is_whitespace())?;
+    // a predicate with a list such as `all(...)` may be wrapped by rustfmt as well
+    if predicate.contains(['\n', '('])
+        || !attr[derive_start..].starts_with(DERIVE)
+        || derive_start + DERIVE.len() != derive_list.range.start
+        || attr[derive_list.range.end..derive.end].trim() != ")"
+        || derive_list.derives.is_empty()
+    {
+        return None;
+    }
+
+    let rustfmt = &reflow.rustfmt;
+    let indent_unit = rustfmt.indent_unit();
+    let items = derive_list
+        .derives
         .iter()
         .map(|d| d.s.as_str())
         .collect::<Vec<_>>()
         .join(", ");
+    let indent_width = rustfmt.width(indent);
+    let unit_width = rustfmt.width(&indent_unit);
+    let items_width = rustfmt.width(&items);
+    let derive_width = DERIVE.len() + items_width + ")".len();
+    let exceeds_threshold = reflow
+        .vertical_threshold
+        .is_some_and(|n| derive_list.derives.len() > n);
 
-    if let Some(_caps) = DERIVE_RE.captures(line) {
-        let replacement = format!("#[derive({sorted_derive_str})]");
-        return DERIVE_RE.replace(line, replacement).into();
+    let line = format!("{CFG_ATTR}{predicate}, {DERIVE}{items}))]");
+    let new_attr = if !exceeds_threshold
+        && indent_width + rustfmt.width(&line) <= rustfmt.max_width
+        && rustfmt.width(predicate) + ", ".len() + derive_width <= rustfmt.attr_fn_like_width()
+    {
+        line
+    } else if !exceeds_threshold
+        && indent_width + unit_width + derive_width + "))".len() <= rustfmt.max_width
+        && items_width <= rustfmt.attr_fn_like_width()
+    {
+        format!(
+            "{CFG_ATTR}\n{indent}{indent_unit}{predicate},\n{indent}{indent_unit}{DERIVE}{items})\n{indent})]"
+        )
+    } else {
+        // unlike a plain derive, the last derive has no trailing comma
+        let derive_indent = format!("{indent}{indent_unit}{indent_unit}");
+        let line_width = rustfmt.max_width - rustfmt.width(&derive_indent);
+        let short = derive_list
+            .derives
+            .iter()
+            .all(|d| rustfmt.width(&d.s) <= SHORT_ITEM_WIDTH);
+        let mut lines: Vec<String> = Vec::new();
+        for (i, derive) in derive_list.derives.iter().enumerate() {
+            let item = if i + 1 < derive_list.derives.len() {
+                format!("{},", derive.s)
+            } else {
+                derive.s.clone()
+            };
+            // short derives are packed into lines, leaving a column for the closing parenthesis
+            match lines.last_mut() {
+                Some(line)
+                    if short
+                        && !exceeds_threshold
+                        && rustfmt.width(line) + " ".len() + rustfmt.width(&item) < line_width =>
+                {
+                    line.push(' ');
+                    line.push_str(&item);
+                }
+                _ => lines.push(item),
+            }
+        }
+        let derives = lines
+            .iter()
+            .map(|line| format!("{derive_indent}{line}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "{CFG_ATTR}\n{indent}{indent_unit}{predicate},\n{indent}{indent_unit}{DERIVE}\n{derives}\n{indent}{indent_unit})\n{indent})]"
+        )
+    };
+    Some(new_attr)
+}
+
+/// Rewrites each derive list in the attribute with its (sorted) derives.
+fn replace_line(attr: &str, derive_lists: &[DeriveList], options: &SortOptions) -> String {
+    let mut new_attr = String::with_capacity(attr.len());
+    let mut copied = 0;
+    for derive_list in derive_lists {
+        new_attr.push_str(&attr[copied..derive_list.range.start]);
+        new_attr.push_str(&format_derive_list(derive_list, options));
+        copied = derive_list.range.end;
     }
+    new_attr.push_str(&attr[copied..]);
+    new_attr
+}
+
+fn format_derive_list(derive_list: &DeriveList, options: &SortOptions) -> String {
+    let derives = &derive_list.derives;
+    let vertical = derive_list.layout == Layout::Vertical;
+    let multiline = derive_list.layout != Layout::Horizontal;
+    let trailing_comma = match (options.trailing_comma, options.style) {
+        (Some(TrailingComma::Always), _) => !derives.is_empty(),
+        (Some(TrailingComma::Never), _) => false,
+        (Some(TrailingComma::Preserve), _) | (None, Style::Preserve) => derive_list.trailing_comma,
+        (None, Style::Normalize) => multiline && derive_list.trailing_comma,
+    };
 
-    if let Some(caps) = CFG_ATTR_RE.captures(line) {
-        let condition = caps.get(1).unwrap().as_str();
-        let replacement = format!("#[cfg_attr({condition}, derive({sorted_derive_str}))]");
-        return CFG_ATTR_RE.replace(line, replacement).into();
+    let has_line_comment = derives.iter().any(|d| d.trailing_comment.contains("//"))
+        || derive_list.dangling_comment.contains("//");
+    if derive_list.layout == Layout::Horizontal && has_line_comment {
+        // a line comment ends the line, e.g. after derives are merged from a multiline list
+        let wrapped = DeriveList {
+            layout: Layout::Wrapped,
+            line_breaks: Vec::new(),
+            ..derive_list.clone()
+        };
+        return format_derive_list(&wrapped, options);
+    }
+
+    let leading = derive_list.leading.as_str();
+    let trailing = derive_list.trailing.as_str();
+    let mut s = String::new();
+    // a line comment at the end of the list must be followed by a line break before the closing parenthesis
+    let push_trailing = |s: &mut String, indent: &str| {
+        let ends_with_line_comment = s
+            .rsplit('\n')
+            .next()
+            .is_some_and(|line| line.contains("//"));
+        if ends_with_line_comment && !trailing.contains('\n') {
+            s.push('\n');
+            s.push_str(indent);
+        } else {
+            s.push_str(trailing);
+        }
+    };
+
+    if vertical {
+        // one derive per line with the original indentation
+        for (i, derive) in derives.iter().enumerate() {
+            s.push_str(leading);
+            s.push_str(&derive.leading_comment);
+            s.push_str(&derive.s);
+            if i + 1 < derives.len() || trailing_comma {
+                s.push(',');
+            }
+            if !derive.trailing_comment.is_empty() {
+                s.push(' ');
+                s.push_str(&derive.trailing_comment);
+            }
+        }
+        if !derive_list.dangling_comment.is_empty() {
+            s.push_str(leading);
+            s.push_str(&derive_list.dangling_comment);
+        }
+        push_trailing(&mut s, leading.trim_start_matches('\n'));
+        return s;
+    }
+
+    let preserve = options.style == Style::Preserve;
+    if derive_list.layout == Layout::Wrapped {
+        // the line breaks stay where they were, and each line comment is followed by one
+        let indent = &derive_list.wrapped_indent();
+        s.push_str(leading);
+        for (i, derive) in derives.iter().enumerate() {
+            s.push_str(&derive.leading_comment);
+            s.push_str(&derive.s);
+            let last = i + 1 == derives.len();
+            if !last || trailing_comma {
+                let separator = derive_list.separators.get(i).filter(|_| preserve);
+                s.push_str(separator.map_or("", |(before, _)| before.as_str()));
+                s.push(',');
+            }
+            if !derive.trailing_comment.is_empty() {
+                s.push(' ');
+                s.push_str(&derive.trailing_comment);
+            }
+            if last {
+                continue;
+            }
+            match derive_list.line_breaks.get(i) {
+                Some(Some(indent)) => {
+                    s.push('\n');
+                    s.push_str(indent);
+                }
+                _ if derive.trailing_comment.contains("//") => {
+                    s.push('\n');
+                    s.push_str(indent);
+                }
+                _ => {
+                    let separator = derive_list
+                        .separators
+                        .get(i)
+                        .filter(|_| preserve && derive.trailing_comment.is_empty());
+                    s.push_str(separator.map_or(" ", |(_, after)| after.as_str()));
+                }
+            }
+        }
+        if !derive_list.dangling_comment.is_empty() {
+            let after_line_comment = derives
+                .last()
+                .is_some_and(|d| d.trailing_comment.contains("//"));
+            if trailing.contains('\n') || after_line_comment {
+                s.push('\n');
+                s.push_str(indent);
+            } else {
+                s.push(' ');
+            }
+            s.push_str(&derive_list.dangling_comment);
+        }
+        push_trailing(&mut s, indent);
+        return s;
+    }
+
+    if preserve {
+        s.push_str(leading);
+    }
+    for (i, derive) in derives.iter().enumerate() {
+        s.push_str(&derive.leading_comment);
+        s.push_str(&derive.s);
+        if !derive.trailing_comment.is_empty() {
+            s.push(' ');
+            s.push_str(&derive.trailing_comment);
+        }
+        let last = i + 1 == derives.len();
+        if !last || trailing_comma {
+            let separator = derive_list.separators.get(i).filter(|_| preserve);
+            let (before, after) = separator.map_or(("", " "), |(b, a)| (b.as_str(), a.as_str()));
+            s.push_str(before);
+            s.push(',');
+            if !last {
+                s.push_str(after);
+            }
+        }
+    }
+    if !derive_list.dangling_comment.is_empty() {
+        s.push(' ');
+        s.push_str(&derive_list.dangling_comment);
+    }
+    if preserve {
+        s.push_str(trailing);
     }
 
-    line.to_string()
+    s
 }
 
 // sort-derives-disable-start
@@ -189,7 +1818,7 @@ mod tests {
     #[test]
     fn test_parse_derive_traits() {
         let line = "#[derive(Debug, cmp::Eq, Foo, std::clone::Clone, Hash, cmp::PartialOrd, foo::bar::Bar)]";
-        let actual = parse_derive_traits(line).unwrap();
+        let actual = parse_single_derive_list(line);
         let expected = vec![
             dt("Debug", "Debug"),
             dt("cmp::Eq", "Eq"),
@@ -203,28 +1832,150 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_derive_traits_with_cfg_attr() {
-        let line = "#[cfg_attr(feature = \"serde\", derive(Serialize, Deserialize))]";
-        let actual = parse_derive_traits(line).unwrap();
+    fn test_parse_derive_traits_with_cfg_attr() {
+        let line = "#[cfg_attr(feature = \"serde\", derive(Serialize, Deserialize))]";
+        let actual = parse_single_derive_list(line);
+        let expected = vec![
+            dt("Serialize", "Serialize"),
+            dt("Deserialize", "Deserialize"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_traits_with_complex_cfg_attr() {
+        let line = "#[cfg_attr(all(feature = \"serde\", not(test)), derive(serde::Serialize, serde::Deserialize, Debug))]";
+        let actual = parse_single_derive_list(line);
+        let expected = vec![
+            dt("serde::Serialize", "Serialize"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("Debug", "Debug"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_traits_multiline() {
+        let line = "#[derive(\n    Debug,\n    serde::Serialize,\n    Clone,\n)]";
+        let actual = parse_single_derive_list(line);
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("serde::Serialize", "Serialize"),
+            dt("Clone", "Clone"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_traits_with_multiline_cfg_attr() {
+        let line = "#[cfg_attr(\n    feature = \"serde\",\n    derive(serde::Serialize, serde::Deserialize)\n)]";
+        let actual = parse_single_derive_list(line);
+        let expected = vec![
+            dt("serde::Serialize", "Serialize"),
+            dt("serde::Deserialize", "Deserialize"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_traits_with_nested_cfg_attr() {
+        let line = "#[cfg_attr(feature = \"a\", cfg_attr(feature = \"b\", derive(B, A)))]";
+        let actual = parse_single_derive_list(line);
+        let expected = vec![dt("B", "B"), dt("A", "A")];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_traits_with_deeply_nested_cfg_attr() {
+        let line = "#[cfg_attr(any(feature = \"a\", test), cfg_attr(feature = \"b,)\", cfg_attr(not(feature = \"c\"), derive(C, b::B, A))))]";
+        let actual = parse_single_derive_list(line);
+        let expected = vec![dt("C", "C"), dt("b::B", "B"), dt("A", "A")];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_lists_with_multiple_attributes_in_cfg_attr() {
+        let line = "#[cfg_attr(feature = \"serde\", derive(Serialize, Deserialize), serde(rename_all = \"camelCase\"))]";
+        let actual = parse_derive_lists(line);
+        let expected = vec![DeriveList {
+            range: 37..59,
+            derives: vec![
+                dt("Serialize", "Serialize"),
+                dt("Deserialize", "Deserialize"),
+            ],
+            separators: vec![("".into(), " ".into())],
+            ..Default::default()
+        }];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_lists_with_multiple_derives_in_cfg_attr() {
+        let line = "#[cfg_attr(test, serde(default), derive(B, A), cfg_attr(unix, derive(D, C)), allow(dead_code))]";
+        let actual: Vec<_> = parse_derive_lists(line)
+            .into_iter()
+            .map(|l| (&line[l.range], l.derives))
+            .collect();
         let expected = vec![
-            dt("Serialize", "Serialize"),
-            dt("Deserialize", "Deserialize"),
+            ("B, A", vec![dt("B", "B"), dt("A", "A")]),
+            ("D, C", vec![dt("D", "D"), dt("C", "C")]),
         ];
         assert_eq!(actual, expected);
     }
 
     #[test]
-    fn test_parse_derive_traits_with_complex_cfg_attr() {
-        let line = "#[cfg_attr(all(feature = \"serde\", not(test)), derive(serde::Serialize, serde::Deserialize, Debug))]";
-        let actual = parse_derive_traits(line).unwrap();
+    fn test_parse_derive_traits_with_comments() {
+        let line = "#[derive(\n    Debug,\n    Clone, // needed by the cache\n    /* TODO remove */ Copy,\n    // first\n    // second\n    Eq /* x */, // y\n    // Hash,\n)]";
+        let actual = parse_derive_lists(line);
+        let expected = vec![DeriveList {
+            range: 9..line.len() - 2,
+            layout: Layout::Vertical,
+            leading: "\n    ".into(),
+            trailing: "\n".into(),
+            derives: vec![
+                dt("Debug", "Debug"),
+                dtc("Clone", "Clone", "", "// needed by the cache"),
+                dtc("Copy", "Copy", "/* TODO remove */ ", ""),
+                dtc("Eq", "Eq", "// first\n    // second\n    ", "/* x */ // y"),
+            ],
+            trailing_comma: true,
+            separators: vec![
+                ("".into(), "\n    ".into()),
+                ("".into(), " ".into()),
+                ("".into(), "\n    ".into()),
+                ("".into(), " ".into()),
+            ],
+            line_breaks: Vec::new(),
+            dangling_comment: "// Hash,".into(),
+        }];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_derive_traits_with_comments_in_line() {
+        let line = "#[derive(Clone /* a */, /* b */ Copy, Debug, /* c */)]";
+        let actual = parse_single_derive_list(line);
         let expected = vec![
-            dt("serde::Serialize", "Serialize"),
-            dt("serde::Deserialize", "Deserialize"),
-            dt("Debug", "Debug"),
+            dtc("Clone", "Clone", "", "/* a */"),
+            dtc("Copy", "Copy", "/* b */ ", ""),
+            dtc("Debug", "Debug", "", "/* c */"),
         ];
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_parse_derive_lists_without_derive() {
+        let lines = [
+            "#[cfg_attr(test, allow(dead_code))]",
+            "#[cfg_attr(feature = \"a\", cfg_attr(feature = \"b\", allow(dead_code)))]",
+            "#[cfg_attr(derive(A, B))]",
+            "#[derive]",
+        ];
+        for line in lines {
+            assert_eq!(parse_derive_lists(line), vec![]);
+        }
+    }
+
     #[test]
     fn test_sort_derive_traits_without_order() {
         let derives = vec![
@@ -242,7 +1993,13 @@ mod tests {
             dt("foo::bar::Bar", "Bar"),
         ];
         let order = None;
-        let actual = sort_derive_traits(&derives, &order, false);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
         let expected = vec![
             dt("foo::bar::Bar", "Bar"),
             dt("std::clone::Clone", "Clone"),
@@ -292,24 +2049,625 @@ mod tests {
             .map(Into::into)
             .collect(),
         );
-        let actual = sort_derive_traits(&derives, &order, false);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("std::clone::Clone", "Clone"),
+            dt("Eq", "Eq"),
+            dt("a::Eq", "Eq"),
+            dt("b::Eq", "Eq"),
+            dt("cmp::Eq", "Eq"),
+            dt("cmp::PartialOrd", "PartialOrd"),
+            dt("Hash", "Hash"),
+            dt("foo::bar::Bar", "Bar"),
+            dt("Foo", "Foo"),
+            dt("a::Foo", "Foo"),
+            dt("b::Foo", "Foo"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_path_order() {
+        let derives = vec![
+            dt("my_macros::Serialize", "Serialize"),
+            dt("Debug", "Debug"),
+            dt(":: serde :: Serialize", "Serialize"),
+            dt("Serialize", "Serialize"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("std::fmt::Debug", "Debug"),
+        ];
+        let order = Some(
+            vec![
+                "::serde::Serialize",
+                "Debug",
+                "Serialize",
+                "std::fmt::Debug",
+            ]
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        );
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
+        let expected = vec![
+            dt(":: serde :: Serialize", "Serialize"),
+            dt("Debug", "Debug"),
+            dt("Serialize", "Serialize"),
+            dt("my_macros::Serialize", "Serialize"),
+            dt("std::fmt::Debug", "Debug"),
+            dt("serde::Deserialize", "Deserialize"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_pattern_order() {
+        let derives = vec![
+            dt("strum::EnumString", "EnumString"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("Debug", "Debug"),
+            dt("Eq", "Eq"),
+            dt("strum::Display", "Display"),
+            dt("PartialEq", "PartialEq"),
+            dt("Hash", "Hash"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("Clone", "Clone"),
+        ];
+        let order = Some(
+            vec!["Debug", "strum::*", "...", "re:^Partial", "*Eq", "Hash"]
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        );
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("strum::Display", "Display"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("strum::EnumString", "EnumString"),
+            dt("Clone", "Clone"),
+            dt("PartialEq", "PartialEq"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("Eq", "Eq"),
+            dt("Hash", "Hash"),
+        ];
+        assert_eq!(actual, expected);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Preserve,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("strum::EnumString", "EnumString"),
+            dt("strum::Display", "Display"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("Clone", "Clone"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("PartialEq", "PartialEq"),
+            dt("Eq", "Eq"),
+            dt("Hash", "Hash"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_groups() {
+        let derives = vec![
+            dt("serde::Serialize", "Serialize"),
+            dt("Debug", "Debug"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("Clone", "Clone"),
+            dt("Hash", "Hash"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("Copy", "Copy"),
+            dt("strum::Display", "Display"),
+            dt("Builder", "Builder"),
+        ];
+        let groups = |order| {
+            HashMap::from([
+                (
+                    "std".to_string(),
+                    DeriveGroup {
+                        derives: vec!["Debug".into(), "Clone".into(), "Copy".into(), "Hash".into()],
+                        order,
+                    },
+                ),
+                (
+                    "crates".to_string(),
+                    DeriveGroup {
+                        derives: vec!["strum::*".into(), "serde::*".into()],
+                        order: GroupOrder::Listed,
+                    },
+                ),
+            ])
+        };
+        let order = Some(
+            vec!["@std", "...", "Hash", "@crates"]
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        );
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions {
+                groups: groups(GroupOrder::Listed),
+                ..Default::default()
+            },
+        );
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("Clone", "Clone"),
+            dt("Copy", "Copy"),
+            dt("Builder", "Builder"),
+            dt("Hash", "Hash"),
+            dt("strum::Display", "Display"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("serde::Serialize", "Serialize"),
+        ];
+        assert_eq!(actual, expected);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Preserve,
+            &Imports::default(),
+            &SortOptions {
+                groups: groups(GroupOrder::Alphabetical),
+                ..Default::default()
+            },
+        );
+        let expected = vec![
+            dt("Clone", "Clone"),
+            dt("Copy", "Copy"),
+            dt("Debug", "Debug"),
+            dt("Builder", "Builder"),
+            dt("Hash", "Hash"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("strum::Display", "Display"),
+            dt("serde::Serialize", "Serialize"),
+            dt("serde::Deserialize", "Deserialize"),
+        ];
+        assert_eq!(actual, expected);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions {
+                groups: groups(GroupOrder::Preserve),
+                ..Default::default()
+            },
+        );
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("Clone", "Clone"),
+            dt("Copy", "Copy"),
+            dt("Builder", "Builder"),
+            dt("Hash", "Hash"),
+            dt("strum::Display", "Display"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("serde::Serialize", "Serialize"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_pair_derive_traits() {
+        let pairs: Vec<(String, String)> = PARTIAL_TRAIT_PAIRS
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .chain([("serde::Serialize".into(), "serde::Deserialize".into())])
+            .collect();
+        let derives = vec![
+            dt("Clone", "Clone"),
+            dt("Debug", "Debug"),
+            dt("Eq", "Eq"),
+            dt("Hash", "Hash"),
+            dt("Ord", "Ord"),
+            dt("PartialEq", "PartialEq"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("serde::Serialize", "Serialize"),
+        ];
+
+        let actual = pair_derive_traits(&derives, &pairs, &Imports::default(), &HashMap::new());
+
+        let expected = vec![
+            dt("Clone", "Clone"),
+            dt("Debug", "Debug"),
+            dt("PartialEq", "PartialEq"),
+            dt("Eq", "Eq"),
+            dt("Hash", "Hash"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("Ord", "Ord"),
+            dt("serde::Serialize", "Serialize"),
+            dt("serde::Deserialize", "Deserialize"),
+        ];
+        assert_eq!(actual, expected);
+
+        // a partial trait without its total trait stays in place
+        let derives = vec![
+            dt("Clone", "Clone"),
+            dt("PartialEq", "PartialEq"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("Zeroable", "Zeroable"),
+        ];
+        assert_eq!(
+            pair_derive_traits(&derives, &pairs, &Imports::default(), &HashMap::new()),
+            derives
+        );
+    }
+
+    #[test]
+    fn test_constraints() {
+        let rules: Vec<String> = vec![
+            "PartialEq < Eq",
+            "PartialOrd < Ord",
+            "Eq < Ord",
+            "Debug < thiserror::Error",
+        ]
+        .into_iter()
+        .map(Into::into)
+        .collect();
+        let constraints = Constraints::new(&rules).unwrap();
+
+        let derives = vec![
+            dt("Clone", "Clone"),
+            dt("Eq", "Eq"),
+            dt("Ord", "Ord"),
+            dt("PartialEq", "PartialEq"),
+            dt("PartialOrd", "PartialOrd"),
+        ];
+        let actual = constraints.apply(&derives, &Imports::default(), &HashMap::new());
+        let expected = vec![
+            dt("Clone", "Clone"),
+            dt("PartialEq", "PartialEq"),
+            dt("Eq", "Eq"),
+            dt("PartialOrd", "PartialOrd"),
+            dt("Ord", "Ord"),
+        ];
+        assert_eq!(actual, expected);
+
+        // `Debug` only has to come before `thiserror::Error`
+        let derives = vec![
+            dt("thiserror::Error", "Error"),
+            dt("Debug", "Debug"),
+            dt("Error", "Error"),
+        ];
+        let actual = constraints.apply(&derives, &Imports::default(), &HashMap::new());
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("thiserror::Error", "Error"),
+            dt("Error", "Error"),
+        ];
+        assert_eq!(actual, expected);
+
+        // already satisfied
+        let derives = vec![dt("Ord", "Ord"), dt("Debug", "Debug")];
+        assert_eq!(
+            constraints.apply(&derives, &Imports::default(), &HashMap::new()),
+            derives
+        );
+    }
+
+    #[test]
+    fn test_constraints_minimal_moves() {
+        let rules: Vec<String> = vec!["E < A".into(), "E < B".into(), "Zeta < Alpha".into()];
+        let constraints = Constraints::new(&rules).unwrap();
+
+        let derives = vec![
+            dt("A", "A"),
+            dt("B", "B"),
+            dt("C", "C"),
+            dt("D", "D"),
+            dt("E", "E"),
+        ];
+        let actual = constraints.apply(&derives, &Imports::default(), &HashMap::new());
+        let expected = vec![
+            dt("E", "E"),
+            dt("A", "A"),
+            dt("B", "B"),
+            dt("C", "C"),
+            dt("D", "D"),
+        ];
+        assert_eq!(actual, expected);
+
+        // the other derives stay in alphabetical order
+        let derives = vec![
+            dt("Alpha", "Alpha"),
+            dt("Beta", "Beta"),
+            dt("Gamma", "Gamma"),
+            dt("Zeta", "Zeta"),
+        ];
+        let actual = constraints.apply(&derives, &Imports::default(), &HashMap::new());
+        let expected = vec![
+            dt("Zeta", "Zeta"),
+            dt("Alpha", "Alpha"),
+            dt("Beta", "Beta"),
+            dt("Gamma", "Gamma"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_constraints_transitive() {
+        let rules: Vec<String> = vec!["A < B".into(), "B < C".into()];
+        let constraints = Constraints::new(&rules).unwrap();
+
+        let derives = vec![dt("C", "C"), dt("A", "A")];
+        let actual = constraints.apply(&derives, &Imports::default(), &HashMap::new());
+        assert_eq!(actual, vec![dt("A", "A"), dt("C", "C")]);
+    }
+
+    #[test]
+    fn test_constraints_error() {
+        let rules: Vec<String> = vec!["A < B".into(), "C < A".into(), "B < D < C".into()];
+        let actual = Constraints::new(&rules).unwrap_err();
+        assert_eq!(actual, "Contradictory constraints: A < B < D < C < A");
+
+        let rules: Vec<String> = vec!["A <".into()];
+        let actual = Constraints::new(&rules).unwrap_err();
+        assert_eq!(actual, "Invalid constraint 'A <': expected 'A < B'");
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_unlisted() {
+        let derives = vec![
+            dt("strum::EnumIter", "EnumIter"),
+            dt("Serialize", "Serialize"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("Debug", "Debug"),
+            dt("strum::Display", "Display"),
+            dt("Clone", "Clone"),
+        ];
+        let order = Some(vec!["Debug".into()]);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::ByCrate,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
+        // `Serialize` is not imported, so it comes after the derives of all the crates
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("Clone", "Clone"),
+            dt("strum::Display", "Display"),
+            dt("strum::EnumIter", "EnumIter"),
+            dt("Serialize", "Serialize"),
+        ];
+        assert_eq!(actual, expected);
+
+        let options = SortOptions {
+            frequencies: HashMap::from([
+                ("Clone".into(), 10),
+                ("Serialize".into(), 5),
+                ("Deserialize".into(), 5),
+                ("Display".into(), 1),
+            ]),
+            ..Default::default()
+        };
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::ByFrequency,
+            &Imports::default(),
+            &options,
+        );
+        let expected = vec![
+            dt("Debug", "Debug"),
+            dt("Clone", "Clone"),
+            dt("serde::Deserialize", "Deserialize"),
+            dt("Serialize", "Serialize"),
+            dt("strum::Display", "Display"),
+            dt("strum::EnumIter", "EnumIter"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_imports() {
+        let src = "use rkyv::{Archive, Serialize};\nuse serde::{self, Deserialize as De};\n";
+        let imports = Imports::new(src, &CodeMap::new(src));
+        let derives = vec![
+            dt("Serialize", "Serialize"),
+            dt("De", "De"),
+            dt("Archive", "Archive"),
+            dt("Clone", "Clone"),
+            dt("Builder", "Builder"),
+            dt("serde::Serialize", "Serialize"),
+        ];
+        let order = Some(vec![
+            "serde::Deserialize".into(),
+            "std::clone::Clone".into(),
+        ]);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::ByCrate,
+            &imports,
+            &SortOptions::default(),
+        );
+        let expected = vec![
+            dt("De", "De"),
+            dt("Clone", "Clone"),
+            dt("Archive", "Archive"),
+            dt("Serialize", "Serialize"),
+            dt("serde::Serialize", "Serialize"),
+            dt("Builder", "Builder"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_aliases() {
+        let derives = vec![
+            dt("my_macros::Builder", "Builder"),
+            dt("serde_derive::Serialize", "Serialize"),
+            dt("Debug", "Debug"),
+            dt("crate::prelude::Deserialize", "Deserialize"),
+            dt("strum::Display", "Display"),
+        ];
+        let order = Some(vec!["Debug".into(), "serde::*".into()]);
+        let options = SortOptions {
+            aliases: HashMap::from([
+                ("serde_derive::Serialize".into(), "serde::Serialize".into()),
+                (
+                    "crate::prelude::Deserialize".into(),
+                    "serde::Deserialize".into(),
+                ),
+                (
+                    "my_macros::Builder".into(),
+                    "derive_builder::Builder".into(),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::ByCrate,
+            &Imports::default(),
+            &options,
+        );
         let expected = vec![
             dt("Debug", "Debug"),
-            dt("std::clone::Clone", "Clone"),
-            dt("Eq", "Eq"),
-            dt("a::Eq", "Eq"),
-            dt("b::Eq", "Eq"),
-            dt("cmp::Eq", "Eq"),
-            dt("cmp::PartialOrd", "PartialOrd"),
-            dt("Hash", "Hash"),
-            dt("foo::bar::Bar", "Bar"),
-            dt("Foo", "Foo"),
-            dt("a::Foo", "Foo"),
-            dt("b::Foo", "Foo"),
+            dt("crate::prelude::Deserialize", "Deserialize"),
+            dt("serde_derive::Serialize", "Serialize"),
+            dt("my_macros::Builder", "Builder"),
+            dt("strum::Display", "Display"),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_derive_traits_with_sort_mode() {
+        let derives = vec![
+            dt("Foo10", "Foo10"),
+            dt("serde_with::skip", "skip"),
+            dt("Foo2", "Foo2"),
+            dt("PartialEq", "PartialEq"),
+            dt("Foo02", "Foo02"),
+            dt("Zeroize", "Zeroize"),
+        ];
+
+        let actual = sort_derive_traits(
+            &derives,
+            &None,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions {
+                sort_mode: SortMode::Lexical,
+                ..Default::default()
+            },
+        );
+        let expected = vec![
+            dt("Foo02", "Foo02"),
+            dt("Foo10", "Foo10"),
+            dt("Foo2", "Foo2"),
+            dt("PartialEq", "PartialEq"),
+            dt("Zeroize", "Zeroize"),
+            dt("serde_with::skip", "skip"),
+        ];
+        assert_eq!(actual, expected);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &None,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions {
+                sort_mode: SortMode::CaseInsensitive,
+                ..Default::default()
+            },
+        );
+        let expected = vec![
+            dt("Foo02", "Foo02"),
+            dt("Foo10", "Foo10"),
+            dt("Foo2", "Foo2"),
+            dt("PartialEq", "PartialEq"),
+            dt("serde_with::skip", "skip"),
+            dt("Zeroize", "Zeroize"),
+        ];
+        assert_eq!(actual, expected);
+
+        let actual = sort_derive_traits(
+            &derives,
+            &None,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions {
+                sort_mode: SortMode::Natural,
+                ..Default::default()
+            },
+        );
+        let expected = vec![
+            dt("Foo02", "Foo02"),
+            dt("Foo2", "Foo2"),
+            dt("Foo10", "Foo10"),
+            dt("PartialEq", "PartialEq"),
+            dt("Zeroize", "Zeroize"),
+            dt("serde_with::skip", "skip"),
         ];
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_sort_mode_case_insensitive() {
+        let mut names = vec!["b", "PartialEq", "a", "B", "parser"];
+        names.sort_by(|a, b| SortMode::CaseInsensitive.compare(a, b));
+        assert_eq!(names, vec!["a", "B", "b", "parser", "PartialEq"]);
+    }
+
+    #[test]
+    fn test_order_pattern_parse() {
+        assert!(OrderPattern::parse("Debug").unwrap().is_none());
+        assert!(OrderPattern::parse("serde::Serialize").unwrap().is_none());
+        assert!(OrderPattern::parse("re:(").is_err());
+
+        let pattern = OrderPattern::parse(":: strum :: *").unwrap().unwrap();
+        assert!(pattern.is_match(&dt("strum::Display", "Display")));
+        assert!(!pattern.is_match(&dt("Display", "Display")));
+
+        let pattern = OrderPattern::parse("Partial??").unwrap().unwrap();
+        assert!(pattern.is_match(&dt("cmp::PartialEq", "PartialEq")));
+        assert!(!pattern.is_match(&dt("PartialOrd", "PartialOrd")));
+    }
+
     #[test]
     fn test_sort_derive_traits_with_order_and_preserve() {
         let derives = vec![
@@ -342,7 +2700,13 @@ mod tests {
             .map(Into::into)
             .collect(),
         );
-        let actual = sort_derive_traits(&derives, &order, true);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Preserve,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
         let expected = vec![
             dt("Debug", "Debug"),
             dt("std::clone::Clone", "Clone"),
@@ -372,7 +2736,13 @@ mod tests {
             dt("G", "G"),
         ];
         let order = Some(vec!["...", "D", "A"].into_iter().map(Into::into).collect());
-        let actual = sort_derive_traits(&derives, &order, false);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
         let expected = vec![
             // ellipsis
             dt("B", "B"),
@@ -404,7 +2774,13 @@ mod tests {
                 .map(Into::into)
                 .collect(),
         );
-        let actual = sort_derive_traits(&derives, &order, false);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
         let expected = vec![
             // head
             dt("B", "B"),
@@ -432,7 +2808,13 @@ mod tests {
             dt("G", "G"),
         ];
         let order = Some(vec!["B", "G", "..."].into_iter().map(Into::into).collect());
-        let actual = sort_derive_traits(&derives, &order, false);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Alphabetical,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
         let expected = vec![
             // head
             dt("B", "B"),
@@ -464,7 +2846,13 @@ mod tests {
                 .map(Into::into)
                 .collect(),
         );
-        let actual = sort_derive_traits(&derives, &order, true);
+        let actual = sort_derive_traits(
+            &derives,
+            &order,
+            Unlisted::Preserve,
+            &Imports::default(),
+            &SortOptions::default(),
+        );
         let expected = vec![
             // head
             dt("B", "B"),
@@ -487,7 +2875,7 @@ mod tests {
             dt("Deserialize", "Deserialize"),
             dt("Serialize", "Serialize"),
         ];
-        let actual = replace_line(line, &sorted_derives);
+        let actual = replace_single_derive_list(line, sorted_derives);
         let expected = "#[cfg_attr(feature = \"serde\", derive(Deserialize, Serialize))]";
         assert_eq!(actual, expected);
     }
@@ -500,15 +2888,941 @@ mod tests {
             dt("serde::Deserialize", "Deserialize"),
             dt("serde::Serialize", "Serialize"),
         ];
-        let actual = replace_line(line, &sorted_derives);
+        let actual = replace_single_derive_list(line, sorted_derives);
         let expected = "#[cfg_attr(all(feature = \"serde\", not(test)), derive(Debug, serde::Deserialize, serde::Serialize))]";
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_replace_line_multiline() {
+        let line = "#[derive(\n        Debug,\n        Clone,\n    )]";
+        let sorted_derives = vec![dt("Clone", "Clone"), dt("Debug", "Debug")];
+        let actual = replace_single_derive_list(line, sorted_derives);
+        let expected = "#[derive(\n        Clone,\n        Debug,\n    )]";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_reader_multiline() {
+        let input = r#"#[derive(
+    Debug,
+    Clone,
+    Copy,
+)]
+struct A;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+struct B;
+"#;
+        let expected = r#"#[derive(
+    Clone,
+    Copy,
+    Debug,
+)]
+struct A;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize)
+)]
+struct B;
+"#;
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_multiline_with_line_numbers() {
+        let input =
+            "#[derive(\n    B,\n    A,\n)]\nstruct X;\n#[derive(\n    D,\n    C,\n)]\nstruct Y;\n";
+        let expected =
+            "#[derive(\n    A,\n    B,\n)]\nstruct X;\n#[derive(\n    D,\n    C,\n)]\nstruct Y;\n";
+        let reader = std::io::Cursor::new(input);
+        let line_numbers = HashSet::from([1]);
+        let options = SortOptions::default();
+        let (_, new_lines, _) = sort_reader(reader, "a.rs", Some(&line_numbers), &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_skips_comments_and_strings() {
+        let input = r##"// e.g. #[derive(B, A)]
+/* #[derive(B, A)]
+#[derive(B, A)] */
+const S: &str = "#[derive(B, A)]";
+const R: &str = r#"
+#[derive(B, A)]
+"#;
+#[derive(B, A)] // #[derive(D, C)]
+struct X;
+"##;
+        let expected = r##"// e.g. #[derive(B, A)]
+/* #[derive(B, A)]
+#[derive(B, A)] */
+const S: &str = "#[derive(B, A)]";
+const R: &str = r#"
+#[derive(B, A)]
+"#;
+#[derive(A, B)] // #[derive(D, C)]
+struct X;
+"##;
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_skips_macro_metavariables() {
+        let input = r#"macro_rules! define {
+    ($name:ident, $($d:path),*) => {
+        #[derive($($d),*)]
+        #[derive(Debug, $crate::Trait)]
+        #[cfg_attr(test, derive(PartialEq, Eq), derive($($d),*))]
+        struct $name;
+    };
+}
+"#;
+        let expected = r#"macro_rules! define {
+    ($name:ident, $($d:path),*) => {
+        #[derive($($d),*)]
+        #[derive(Debug, $crate::Trait)]
+        #[cfg_attr(test, derive(Eq, PartialEq), derive($($d),*))]
+        struct $name;
+    };
+}
+"#;
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_include_comments() {
+        let input = "// #[derive(B, A)]\nconst S: &str = \"#[derive(D, C)]\";\n";
+        let expected = "// #[derive(A, B)]\nconst S: &str = \"#[derive(C, D)]\";\n";
+        let options = SortOptions {
+            include_comments: true,
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_multiple_attributes_in_line() {
+        let input = r#"#[derive(B, A)] #[cfg_attr(test, derive(Z, Y))] struct X;
+#[derive(Debug, Clone)] pub struct Foo;
+#[cfg_attr(test, derive(D, C))] #[derive(B, A)] #[derive(F, E)]
+#[derive(
+    B,
+    A,
+)] #[derive(D, C)]
+struct Y;
+"#;
+        let expected = r#"#[derive(A, B)] #[cfg_attr(test, derive(Y, Z))] struct X;
+#[derive(Clone, Debug)] pub struct Foo;
+#[cfg_attr(test, derive(C, D))] #[derive(A, B)] #[derive(E, F)]
+#[derive(
+    A,
+    B,
+)] #[derive(C, D)]
+struct Y;
+"#;
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_replace_line_with_nested_cfg_attr() {
+        let line = "#[cfg_attr(feature = \"a\", cfg_attr(feature = \"b\", derive(B, A)))]";
+        let sorted_derives = vec![dt("A", "A"), dt("B", "B")];
+        let actual = replace_single_derive_list(line, sorted_derives);
+        let expected = "#[cfg_attr(feature = \"a\", cfg_attr(feature = \"b\", derive(A, B)))]";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_multiple_attributes_in_cfg_attr() {
+        let input = r#"#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+#[cfg_attr(test, derive(B, A), serde(with = "x(y, z)"), derive(D, C))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+struct X;
+"#;
+        let expected = r#"#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(rename_all = "camelCase"))]
+#[cfg_attr(test, derive(A, B), serde(with = "x(y, z)"), derive(C, D))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+struct X;
+"#;
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_comments() {
+        let input = r#"#[derive(
+    Debug,
+    Clone, // needed by the cache
+    /* TODO remove */ Copy,
+    // comparison
+    PartialEq,
+    // Hash,
+)]
+struct X;
+
+#[derive(Debug, /* TODO remove */ Copy, Clone /* cache */)]
+struct Y;
+"#;
+        let expected = r#"#[derive(
+    Clone, // needed by the cache
+    /* TODO remove */ Copy,
+    Debug,
+    // comparison
+    PartialEq,
+    // Hash,
+)]
+struct X;
+
+#[derive(Clone /* cache */, /* TODO remove */ Copy, Debug)]
+struct Y;
+"#;
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_normalize_style() {
+        let input = "#[derive( B,A, )]\n#[derive(D , C)]\n#[derive(\n    F,\n    E,\n)]\n";
+        let expected = "#[derive(A, B)]\n#[derive(C, D)]\n#[derive(\n    E,\n    F,\n)]\n";
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_wrapped_layout() {
+        // rustfmt's layout when the derives fit on a single line between the brackets
+        let sorted = "#[derive(\n    Clone, Debug, Deserialize, Serialize,\n)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(sorted, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), sorted);
+
+        let input = "#[derive(\n    Serialize, Debug,\n    Deserialize, Clone,\n)]\nstruct A;\n";
+        let expected = "#[derive(\n    Clone, Debug,\n    Deserialize, Serialize,\n)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_line_comments_in_wrapped_layout() {
+        let cases = [
+            // on the last derive
+            (
+                "#[derive(B, A, // tail\n)]\n",
+                "#[derive(A, // tail\n    B,\n)]\n",
+            ),
+            // on a middle derive
+            (
+                "#[derive(\n    C, B, // mid\n    A,\n)]\n",
+                "#[derive(\n    A, B, // mid\n    C,\n)]\n",
+            ),
+            // on a derive sorted to the end
+            (
+                "#[derive(B, // x\n    A)]\n",
+                "#[derive(A,\n    B // x\n    )]\n",
+            ),
+            (
+                "#[derive(\n    B, // x\n    A)]\n",
+                "#[derive(\n    A,\n    B // x\n    )]\n",
+            ),
+        ];
+        for (input, expected) in cases {
+            let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+            assert_eq!(new_lines.concat(), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn test_sort_reader_with_visual_indent() {
+        let input = "#[derive(Debug,\n         Clone)]\nstruct A;\n";
+        let expected = "#[derive(Clone,\n         Debug)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(input, &SortOptions::default()).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_preserve_style() {
+        let input = "#[derive( B,A, )]\n#[derive(D , C)]\n#[derive(Z,Y, X)]\n#[derive(\n    F,\n    E,\n)]\n";
+        let expected = "#[derive( A,B, )]\n#[derive(C , D)]\n#[derive(X,Y, Z)]\n#[derive(\n    E,\n    F,\n)]\n";
+        let options = SortOptions {
+            style: Style::Preserve,
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_trailing_comma() {
+        let input = "#[derive(B, A,)]\n#[derive(D, C)]\n#[derive(\n    F,\n    E\n)]\n#[derive(\n    H,\n    G,\n)]\n";
+        let cases = [
+            (
+                Style::Normalize,
+                TrailingComma::Always,
+                "#[derive(A, B,)]\n#[derive(C, D,)]\n#[derive(\n    E,\n    F,\n)]\n#[derive(\n    G,\n    H,\n)]\n",
+            ),
+            (
+                Style::Normalize,
+                TrailingComma::Never,
+                "#[derive(A, B)]\n#[derive(C, D)]\n#[derive(\n    E,\n    F\n)]\n#[derive(\n    G,\n    H\n)]\n",
+            ),
+            (
+                Style::Normalize,
+                TrailingComma::Preserve,
+                "#[derive(A, B,)]\n#[derive(C, D)]\n#[derive(\n    E,\n    F\n)]\n#[derive(\n    G,\n    H,\n)]\n",
+            ),
+            (
+                Style::Preserve,
+                TrailingComma::Never,
+                "#[derive(A, B)]\n#[derive(C, D)]\n#[derive(\n    E,\n    F\n)]\n#[derive(\n    G,\n    H\n)]\n",
+            ),
+        ];
+        for (style, trailing_comma, expected) in cases {
+            let options = SortOptions {
+                style,
+                trailing_comma: Some(trailing_comma),
+                ..Default::default()
+            };
+            let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+            assert_eq!(
+                new_lines.concat(),
+                expected,
+                "{style:?}, {trailing_comma:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sort_reader_with_reflow() {
+        let input = r#"#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, serde::Serialize)]
+struct A;
+
+mod b {
+    #[derive(Debug, Clone, Copy)]
+    struct B;
+
+    #[derive(
+        Debug,
+        Clone,
+    )]
+    struct C;
+
+    #[derive(
+        Debug,
+        Clone, // cache
+    )]
+    struct D;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)] struct E;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct F;
+"#;
+        let expected = r#"#[derive(
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    serde::Serialize,
+)]
+struct A;
+
+mod b {
+    #[derive(Clone, Copy, Debug)]
+    struct B;
+
+    #[derive(Clone, Debug)]
+    struct C;
+
+    #[derive(
+        Clone, // cache
+        Debug,
+    )]
+    struct D;
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)] struct E;
+
+#[derive(
+    Clone, Debug, Deserialize, Eq, PartialEq, Serialize,
+)]
+struct F;
+"#;
+        let options = SortOptions {
+            reflow: Some(Reflow {
+                rustfmt: RustfmtConfig {
+                    max_width: 60,
+                    ..Default::default()
+                },
+                vertical_threshold: None,
+            }),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_reflow_cfg_attr() {
+        let input = r#"#[cfg_attr(test, derive(Debug, Clone))]
+struct A;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+)]
+struct B;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize, Debug, Clone))]
+struct C;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize, Debug))]
+struct D;
+
+#[cfg_attr(all(test, feature = "serde"), derive(Debug, Clone))]
+struct E;
+
+#[cfg_attr(feature = "serde", derive(PartialEq, Hash, Eq, Debug, Copy, Clone))]
+struct F;
+"#;
+        let expected = r#"#[cfg_attr(test, derive(Clone, Debug))]
+struct A;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize)
+)]
+struct B;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(
+        Clone,
+        Debug,
+        Deserialize,
+        Serialize
+    )
+)]
+struct C;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(
+        Debug,
+        serde::Deserialize,
+        serde::Serialize
+    )
+)]
+struct D;
+
+#[cfg_attr(all(test, feature = "serde"), derive(Clone, Debug))]
+struct E;
+
+#[cfg_attr(
+    feature = "serde",
+    derive(
+        Clone, Copy, Debug, Eq, Hash,
+        PartialEq
+    )
+)]
+struct F;
+"#;
+        let options = SortOptions {
+            reflow: Some(Reflow {
+                rustfmt: RustfmtConfig {
+                    max_width: 40,
+                    ..Default::default()
+                },
+                vertical_threshold: None,
+            }),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_reflow_scaled_width() {
+        // `attr_fn_like_width` is 84 with `max_width = 115`, so the 82 columns of derives fit on one line
+        let input = "mod m {\n    #[cfg_attr(test, derive(B7xxx, A6xxxxxxx, A5xxxxxxx, A4xxxxxxx, A3xxxxxxx, A2xxxxxxx, A1xxxxxxx, A0xxxxxxx))]\n    struct S;\n}\n";
+        let expected = "mod m {\n    #[cfg_attr(\n        test,\n        derive(A0xxxxxxx, A1xxxxxxx, A2xxxxxxx, A3xxxxxxx, A4xxxxxxx, A5xxxxxxx, A6xxxxxxx, B7xxx)\n    )]\n    struct S;\n}\n";
+        let options = SortOptions {
+            reflow: Some(Reflow {
+                rustfmt: RustfmtConfig {
+                    max_width: 115,
+                    ..Default::default()
+                },
+                vertical_threshold: None,
+            }),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_reflow_hard_tabs_and_threshold() {
+        let input = "mod a {\n\t#[derive(Debug, Clone, Copy)]\n\tstruct A;\n\t#[derive(Debug, Clone)]\n\tstruct B;\n}\n";
+        let expected = "mod a {\n\t#[derive(\n\t\tClone,\n\t\tCopy,\n\t\tDebug,\n\t)]\n\tstruct A;\n\t#[derive(Clone, Debug)]\n\tstruct B;\n}\n";
+        let options = SortOptions {
+            reflow: Some(Reflow {
+                rustfmt: RustfmtConfig {
+                    hard_tabs: true,
+                    ..Default::default()
+                },
+                vertical_threshold: Some(2),
+            }),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_merge() {
+        let input = r#"/// Doc
+#[derive(Debug, Clone)]
+/// More doc
+#[serde(rename_all = "camelCase")]
+#[derive(Serialize)]
+    #[derive(
+        Default,
+    )]
+struct A;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(Eq))]
+#[derive(Serialize)]
+struct B;
+
+#[derive(B)] #[derive(A)] struct C;
+
+#[derive(B)]
+struct D;
+#[derive(A)]
+struct E;
+
+#[derive(B)]
+// sort-derives-disable-next-line
+#[derive(A)]
+struct F;
+"#;
+        let expected = r#"/// Doc
+#[derive(Clone, Debug, Default, Serialize)]
+/// More doc
+#[serde(rename_all = "camelCase")]
+struct A;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(Eq))]
+#[derive(Serialize)]
+struct B;
+
+#[derive(A, B)] struct C;
+
+#[derive(B)]
+struct D;
+#[derive(A)]
+struct E;
+
+#[derive(B)]
+// sort-derives-disable-next-line
+#[derive(A)]
+struct F;
+"#;
+        let options = SortOptions {
+            merge: true,
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_merge_line_comments() {
+        let input = "#[derive(Debug)]\n#[derive(\n    Clone, // cache\n    Copy,\n)]\nstruct A;\n";
+        let expected = "#[derive(\n    Clone, // cache\n    Copy,\n    Debug,\n)]\nstruct A;\n";
+        let options = SortOptions {
+            merge: true,
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_split() {
+        let input = r#"#[derive(serde::Serialize, Debug, Builder, serde::Deserialize, Clone)]
+struct A;
+
+    #[derive(Deserialize, Eq, Serialize)] struct B;
+
+#[derive(
+    Debug,
+    Clone, // cache
+    serde::Serialize,
+)]
+struct C;
+
+#[derive(Debug, Clone)]
+struct D;
+
+#[cfg_attr(test, derive(Serialize, Debug))]
+struct E;
+"#;
+        let expected = r#"#[derive(Clone, Debug)]
+#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Builder)]
+struct A;
+
+    #[derive(Eq)] #[derive(Deserialize)] #[derive(Serialize)] struct B;
+
+#[derive(
+    Clone, // cache
+    Debug,
+)]
+#[derive(
+    serde::Serialize,
+)]
+struct C;
+
+#[derive(Clone, Debug)]
+struct D;
+
+#[cfg_attr(test, derive(Debug, Serialize))]
+struct E;
+"#;
+        let options = SortOptions {
+            split: Some(vec![
+                vec!["Clone".into(), "Debug".into(), "Eq".into()],
+                vec!["serde::Serialize".into(), "Deserialize".into()],
+            ]),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_split_imported() {
+        let input = "use serde::Serialize;\n\n#[derive(Serialize, Debug)]\nstruct A;\n";
+        let expected =
+            "use serde::Serialize;\n\n#[derive(Serialize)]\n#[derive(Debug)]\nstruct A;\n";
+        let options = SortOptions {
+            split: Some(vec![vec!["serde::Serialize".into()], vec!["Debug".into()]]),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_pairs_imported() {
+        let input = "use serde::{Deserialize, Serialize};\n\n#[derive(Deserialize, Debug, Serialize)]\nstruct A;\n";
+        let expected = "use serde::{Deserialize, Serialize};\n\n#[derive(Debug, Serialize, Deserialize)]\nstruct A;\n";
+        let options = SortOptions {
+            pairs: vec![("serde::Serialize".into(), "serde::Deserialize".into())],
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_constraints_imported() {
+        let input = "use serde::Serialize;\n\n#[derive(Debug, Serialize)]\nstruct A;\n";
+        let expected = "use serde::Serialize;\n\n#[derive(Serialize, Debug)]\nstruct A;\n";
+        let options = SortOptions {
+            constraints: Some(Constraints::new(&["serde::Serialize < Debug".into()]).unwrap()),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_dedupe_imported() {
+        let input = "use serde::Serialize;\n\n#[derive(Serialize, serde::Serialize)]\nstruct A;\n";
+        let expected = "use serde::Serialize;\n\n#[derive(Serialize)]\nstruct A;\n";
+        let options = SortOptions {
+            dedupe: true,
+            ..Default::default()
+        };
+        let (_, new_lines, warnings) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_sort_reader_with_aliases_in_split_pairs_constraints_and_dedupe() {
+        let aliases = HashMap::from([
+            ("serde_derive::Serialize".into(), "serde::Serialize".into()),
+            (
+                "crate::prelude::Deserialize".into(),
+                "serde::Deserialize".into(),
+            ),
+        ]);
+        let input =
+            "#[derive(crate::prelude::Deserialize, Debug, serde_derive::Serialize)]\nstruct A;\n";
+
+        let options = SortOptions {
+            split: Some(vec![vec!["serde::Serialize".into()]]),
+            aliases: aliases.clone(),
+            ..Default::default()
+        };
+        let expected = "#[derive(serde_derive::Serialize)]\n#[derive(Debug, crate::prelude::Deserialize)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+
+        let options = SortOptions {
+            pairs: vec![("serde::Serialize".into(), "serde::Deserialize".into())],
+            aliases: aliases.clone(),
+            ..Default::default()
+        };
+        let expected =
+            "#[derive(Debug, serde_derive::Serialize, crate::prelude::Deserialize)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+
+        let options = SortOptions {
+            constraints: Some(Constraints::new(&["serde::Deserialize < Debug".into()]).unwrap()),
+            aliases: aliases.clone(),
+            ..Default::default()
+        };
+        let expected =
+            "#[derive(crate::prelude::Deserialize, Debug, serde_derive::Serialize)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+
+        let input = "#[derive(serde_derive::Serialize, serde::Serialize)]\nstruct A;\n";
+        let options = SortOptions {
+            dedupe: true,
+            aliases,
+            ..Default::default()
+        };
+        let expected = "#[derive(serde_derive::Serialize)]\nstruct A;\n";
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    #[test]
+    fn test_resolved_path() {
+        let cases = [
+            ("Debug", "core::fmt::Debug"),
+            ("std::fmt::Debug", "core::fmt::Debug"),
+            (":: core :: fmt :: Debug", "core::fmt::Debug"),
+            ("serde::Serialize", "serde::Serialize"),
+            ("Serialize", "Serialize"),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(dt(s, "").resolved_path(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn test_find_duplicate_derives() {
+        let derives = vec![
+            dt("Debug", "Debug"),
+            dt("Clone", "Clone"),
+            dt("std::fmt::Debug", "Debug"),
+            dt("Eq", "Eq"),
+        ];
+        let mut seen = HashSet::from(["core::cmp::Eq".to_string()]);
+
+        let actual =
+            find_duplicate_derives(&derives, &mut seen, &Imports::default(), &HashMap::new());
+
+        assert_eq!(actual, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_sort_reader_with_duplicates() {
+        let input = r#"#[derive(Debug, Clone, std::fmt::Debug)]
+#[derive(Clone)]
+#[serde(rename_all = "camelCase")]
+#[derive(Eq, core::clone::Clone)]
+struct A;
+
+#[derive(Debug)]
+#[cfg_attr(test, derive(Debug, Eq, Eq))]
+struct B;
+"#;
+        let (_, new_lines, warnings) = sort_stdin(input, &SortOptions::default()).unwrap();
+        let expected_lines = input
+            .replace("Debug, Clone, std", "Clone, Debug, std")
+            .replace("Eq, core::clone::Clone", "core::clone::Clone, Eq");
+        assert_eq!(new_lines.concat(), expected_lines);
+        let expected = vec![
+            Warning {
+                line: 1,
+                message: "duplicate derive `std::fmt::Debug`".into(),
+                fixed: false,
+            },
+            Warning {
+                line: 2,
+                message: "duplicate derive `Clone`".into(),
+                fixed: false,
+            },
+            Warning {
+                line: 4,
+                message: "duplicate derive `core::clone::Clone`".into(),
+                fixed: false,
+            },
+            Warning {
+                line: 8,
+                message: "duplicate derive `Eq`".into(),
+                fixed: false,
+            },
+        ];
+        assert_eq!(warnings, expected);
+    }
+
+    #[test]
+    fn test_sort_reader_with_dedupe() {
+        let input = r#"#[derive(Debug, Clone, std::fmt::Debug)]
+#[derive(Clone)]
+#[serde(rename_all = "camelCase")]
+#[derive(Eq, core::clone::Clone)]
+struct A;
+
+#[derive(Debug)]
+#[cfg_attr(test, derive(Debug, Eq, Eq))]
+struct B;
+
+#[derive(Clone)] #[derive(Clone)] struct C;
+"#;
+        let expected = r#"#[derive(Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+#[derive(Eq)]
+struct A;
+
+#[derive(Debug)]
+#[cfg_attr(test, derive(Debug, Eq))]
+struct B;
+
+#[derive(Clone)] struct C;
+"#;
+        let options = SortOptions {
+            dedupe: true,
+            ..Default::default()
+        };
+        let (_, new_lines, warnings) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+        assert_eq!(warnings.len(), 5);
+        assert!(warnings.iter().all(|w| w.fixed));
+    }
+
+    #[test]
+    fn test_find_item() {
+        let cases = [
+            ("#[derive(A)] struct A;", Some((ItemKind::Struct, "A"))),
+            (
+                "#[derive(A)]\n/// doc\n#[repr(u8)]\npub enum B {}",
+                Some((ItemKind::Enum, "B")),
+            ),
+            (
+                "#[derive(A)] pub(in crate::a) union C {}",
+                Some((ItemKind::Union, "C")),
+            ),
+            (
+                "#[derive(A)] pub /* c */ (crate) struct /* d */ D<T>(T);",
+                Some((ItemKind::Struct, "D")),
+            ),
+            ("#[derive(A)] fn a() {}", None),
+            ("#[derive(A)]", None),
+        ];
+        for (text, expected) in cases {
+            let code_map = CodeMap::new(text);
+            assert_eq!(find_item(text, 12, &code_map), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn test_sort_reader_with_kind_orders() {
+        let input = r#"#[derive(Debug, Clone, Copy)]
+enum A {}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct B;
+
+#[cfg_attr(test, derive(Debug, Clone, Copy))]
+/// doc
+union C {}
+"#;
+        let expected = r#"#[derive(Copy, Clone, Debug)]
+enum A {}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct B;
+
+#[cfg_attr(test, derive(Debug, Clone, Copy))]
+/// doc
+union C {}
+"#;
+        let options = SortOptions {
+            custom_order: Some(vec!["Debug".into(), "Clone".into()]),
+            unlisted: Unlisted::Preserve,
+            kind_orders: HashMap::from([(
+                ItemKind::Enum,
+                KindOrder {
+                    custom_order: Some(vec!["Copy".into(), "Clone".into()]),
+                    unlisted: Unlisted::Alphabetical,
+                },
+            )]),
+            ..Default::default()
+        };
+        let (_, new_lines, _) = sort_stdin(input, &options).unwrap();
+        assert_eq!(new_lines.concat(), expected);
+    }
+
+    fn parse_single_derive_list(line: &str) -> Vec<DeriveTrait> {
+        let mut derive_lists = parse_derive_lists(line);
+        assert_eq!(derive_lists.len(), 1);
+        derive_lists.remove(0).derives
+    }
+
+    fn replace_single_derive_list(line: &str, sorted_derives: Vec<DeriveTrait>) -> String {
+        let mut derive_lists = parse_derive_lists(line);
+        assert_eq!(derive_lists.len(), 1);
+        derive_lists[0].derives = sorted_derives;
+        replace_line(line, &derive_lists, &SortOptions::default())
+    }
+
     fn dt(s: &str, base_name: &str) -> DeriveTrait {
         DeriveTrait {
             s: s.into(),
             base_name: base_name.into(),
+            ..Default::default()
+        }
+    }
+
+    fn dtc(s: &str, base_name: &str, leading_comment: &str, trailing_comment: &str) -> DeriveTrait {
+        DeriveTrait {
+            s: s.into(),
+            base_name: base_name.into(),
+            leading_comment: leading_comment.into(),
+            trailing_comment: trailing_comment.into(),
         }
     }
 }
@@ -1,6 +1,6 @@
 use std::path::{Path, PathBuf};
 
-use assert_cmd::cargo::cargo_bin_cmd;
+use assert_cmd::{assert::OutputAssertExt, cargo::cargo_bin_cmd};
 use dircpy::copy_dir;
 use tempfile::TempDir;
 
@@ -54,6 +54,197 @@ fn test_exclude() -> Result<()> {
     compare(dir, "exclude")
 }
 
+#[test]
+fn test_include_comments() -> Result<()> {
+    let dir = setup_input()?;
+    let config_path = config_file_path("include_comments.toml")?;
+    execute(&["--config", &config_path], dir.path())?;
+    compare(dir, "include_comments")
+}
+
+#[test]
+fn test_overrides() -> Result<()> {
+    let dir = setup_input()?;
+    let config_path = config_file_path("overrides.toml")?;
+    execute(&["--config", &config_path], dir.path())?;
+    compare(dir, "overrides")
+}
+
+#[test]
+fn test_overrides_preserve_over_unlisted() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(
+        dir.path().join("sort-derives.toml"),
+        "unlisted = \"alphabetical\"\n\n[[overrides]]\nfiles = [\"gen/**\"]\npreserve = true\n",
+    )?;
+    std::fs::create_dir(dir.path().join("gen"))?;
+    std::fs::write(
+        dir.path().join("gen/a.rs"),
+        "#[derive(B, C, A)]\nstruct A;\n",
+    )?;
+    std::fs::write(dir.path().join("b.rs"), "#[derive(B, C, A)]\nstruct B;\n")?;
+
+    execute(&[], dir.path())?;
+
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("gen/a.rs"))?,
+        "#[derive(B, C, A)]\nstruct A;\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("b.rs"))?,
+        "#[derive(A, B, C)]\nstruct B;\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_infer_order() -> Result<()> {
+    let dir = setup_input()?;
+
+    let assert = cargo_bin_cmd!()
+        .arg(BASE_COMMAND_NAME)
+        .arg("infer-order")
+        .current_dir(dir.path())
+        .assert()
+        .success();
+
+    let expected = r#"order = [
+  "Debug",
+  "Clone",
+  "PartialEq",
+  "Eq",
+  "Hash",
+  "Serialize",
+  "Copy",
+  "PartialOrd",
+  "Default",
+  "Deserialize",
+  "Ord",
+]
+# 3 of 8 lines with derives (37.5%) would change with this order
+"#;
+    assert_eq!(
+        String::from_utf8(assert.get_output().stdout.clone())?,
+        expected
+    );
+    // files are not updated
+    compare(dir, "../input")
+}
+
+#[test]
+fn test_infer_order_counts_lines() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(
+        dir.path().join("a.rs"),
+        "#[derive(\n    B,\n    A,\n    C,\n)]\nstruct A;\n",
+    )?;
+    std::fs::write(dir.path().join("b.rs"), "#[derive(A, B)]\nstruct B;\n")?;
+    std::fs::write(dir.path().join("c.rs"), "#[derive(A, B, C)]\nstruct C;\n")?;
+
+    let assert = cargo_bin_cmd!()
+        .arg(BASE_COMMAND_NAME)
+        .arg("infer-order")
+        .current_dir(dir.path())
+        .assert()
+        .success();
+
+    let expected = r#"order = [
+  "A",
+  "B",
+  "C",
+]
+# 2 of 7 lines with derives (28.6%) would change with this order
+"#;
+    assert_eq!(
+        String::from_utf8(assert.get_output().stdout.clone())?,
+        expected
+    );
+    Ok(())
+}
+
+#[test]
+fn test_order_command() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    // swaps the two derives of every derive list, and the constraint is not applied to its order
+    std::fs::write(
+        dir.path().join("sort-derives.toml"),
+        r#"order_command = ["sh", "-c", "while read -r line; do echo '[1, 0]'; done"]
+constraints = ["A < B"]
+"#,
+    )?;
+    std::fs::write(dir.path().join("a.rs"), "#[derive(A, B)]\nstruct A;\n")?;
+    std::fs::write(dir.path().join("b.rs"), "#[derive(A, B, C)]\nstruct B;\n")?;
+
+    let assert = cargo_bin_cmd!()
+        .arg(BASE_COMMAND_NAME)
+        .current_dir(dir.path())
+        .assert()
+        .failure()
+        .code(1);
+
+    assert_eq!(
+        String::from_utf8(assert.get_output().stderr.clone())?,
+        "error: ./b.rs: line 1: order command returned `[1, 0]`, which is not a permutation of 3 derives\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("a.rs"))?,
+        "#[derive(B, A)]\nstruct A;\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("b.rs"))?,
+        "#[derive(A, B, C)]\nstruct B;\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reflow_matches_rustfmt() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("sort-derives.toml"), "reflow = true\n")?;
+    std::fs::write(
+        dir.path().join("rustfmt.toml"),
+        "max_width = 40\ntab_spaces = 2\n",
+    )?;
+    let input = r#"#[derive(Serialize, Deserialize, Debug, Clone)]
+struct A;
+
+#[derive(Debug, Clone)]
+struct B;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+struct C;
+
+mod m {
+  #[derive(
+    Debug,
+    Clone,
+  )]
+  struct D;
+
+  #[cfg_attr(test, derive(Debug, Clone))]
+  struct E;
+
+  #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+  struct F;
+
+  #[cfg_attr(feature = "serde", derive(Serialize, Deserialize, Debug, Clone, PartialEq))]
+  struct G;
+}
+"#;
+    std::fs::write(dir.path().join("a.rs"), input)?;
+
+    execute(&[], dir.path())?;
+
+    let output = std::fs::read_to_string(dir.path().join("a.rs"))?;
+    assert!(output.contains("Clone, Debug, Deserialize, Serialize,"));
+    std::process::Command::new("rustfmt")
+        .args(["--edition", "2024", "--check", "a.rs"])
+        .current_dir(dir.path())
+        .assert()
+        .success();
+    Ok(())
+}
+
 #[test]
 fn test_stdin() -> Result<()> {
     let dir = tempfile::tempdir()?;
@@ -93,6 +284,49 @@ fn test_stdin_check() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_stdin_check_duplicates() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = "#[derive(Clone, Debug)]\n#[derive(Clone)]\nstruct A;\n";
+
+    let assert = cargo_bin_cmd!()
+        .arg(BASE_COMMAND_NAME)
+        .arg("--stdin")
+        .arg("--check")
+        .current_dir(dir.path())
+        .write_stdin(input)
+        .assert()
+        .failure()
+        .code(1);
+
+    assert_eq!(
+        String::from_utf8(assert.get_output().stdout.clone())?,
+        "<stdin>:2: duplicate derive `Clone`\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_stdin_preset() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let input = "#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]\nstruct A;\n";
+
+    let assert = cargo_bin_cmd!()
+        .arg(BASE_COMMAND_NAME)
+        .arg("--stdin")
+        .args(["--preset", "std-first", "--order", "..., Debug"])
+        .current_dir(dir.path())
+        .write_stdin(input)
+        .assert()
+        .success();
+
+    assert_eq!(
+        String::from_utf8(assert.get_output().stdout.clone())?,
+        "#[derive(Clone, Copy, PartialEq, Eq, Serialize, Debug)]\nstruct A;\n"
+    );
+    Ok(())
+}
+
 fn setup_input() -> Result<TempDir> {
     let temp_dir = tempfile::tempdir()?;
     let temp_dir_path = temp_dir.path();